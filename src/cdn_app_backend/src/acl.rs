@@ -0,0 +1,74 @@
+// Per-file access control lists. The owner of a file and admins hold every
+// permission; anyone else needs a grant, either to their principal or to one
+// of their roles. Public files are readable by everyone on top of that.
+use crate::Role;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+const MAX_GRANTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+// Visibility set on a file or a directory. Files without their own setting
+// take it from the nearest directory that has one, and are private otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum Grantee {
+    Principal(Principal),
+    Role(Role),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct FileAcl {
+    pub read: Vec<Grantee>,
+    pub write: Vec<Grantee>,
+    pub delete: Vec<Grantee>,
+}
+
+// What a file gets when the uploader doesn't pass an ACL, and what files
+// uploaded before ACLs existed decode with: readable by every role, changed
+// only by the owner and admins.
+impl Default for FileAcl {
+    fn default() -> Self {
+        FileAcl {
+            read: vec![
+                Grantee::Role(Role::Admin),
+                Grantee::Role(Role::Publisher),
+                Grantee::Role(Role::Viewer),
+            ],
+            write: Vec::new(),
+            delete: Vec::new(),
+        }
+    }
+}
+
+impl FileAcl {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if [&self.read, &self.write, &self.delete].iter().any(|grants| grants.len() > MAX_GRANTS) {
+            return Err(format!("At most {} grants per permission are allowed", MAX_GRANTS));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn grants(&self, permission: Permission, caller: Principal, roles: &[Role]) -> bool {
+        let grantees = match permission {
+            Permission::Read => &self.read,
+            Permission::Write => &self.write,
+            Permission::Delete => &self.delete,
+        };
+        grantees.iter().any(|grantee| match grantee {
+            Grantee::Principal(principal) => *principal == caller,
+            Grantee::Role(role) => roles.contains(role),
+        })
+    }
+}
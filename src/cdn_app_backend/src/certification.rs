@@ -0,0 +1,86 @@
+// Response certification for the HTTP gateway. Every servable path maps to
+// the SHA-256 of its body in a certified tree under the `http_assets` label,
+// whose root is published with `set_certified_data`.
+use crate::http::HeaderField;
+use crate::State;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use std::cell::RefCell;
+
+const LABEL_ASSETS: &[u8] = b"http_assets";
+
+thread_local! {
+    // Derived from `State`, so it is rebuilt after upgrades rather than persisted.
+    static ASSET_HASHES: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+pub(crate) fn file_path(file_id: &str) -> String {
+    format!("/files/{}", file_id)
+}
+
+// Recomputes the entries for both paths a file is reachable under: its id
+// and the logical path it is (or was) published at.
+pub(crate) fn certify_file(state: &State, file_id: &str, path: &str) {
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        let by_id = state.files.get(file_id).filter(|metadata| metadata.is_active);
+        set_entry(&mut tree, file_path(file_id), by_id.and_then(|m| m.file_hash.as_deref()));
+        let by_path = state.find_by_path(path);
+        set_entry(&mut tree, path.to_string(), by_path.and_then(|m| m.file_hash.as_deref()));
+    });
+    publish();
+}
+
+pub(crate) fn rebuild(state: &State) {
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for metadata in state.files.values().filter(|metadata| metadata.is_active) {
+            set_entry(&mut tree, file_path(&metadata.id), metadata.file_hash.as_deref());
+            set_entry(&mut tree, metadata.path.clone(), metadata.file_hash.as_deref());
+        }
+    });
+    publish();
+}
+
+// Builds the `IC-Certificate` header proving the body hash stored for `path`.
+// Only available in query calls, where the system provides a certificate.
+pub(crate) fn certificate_header(path: &str) -> Option<HeaderField> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    ASSET_HASHES.with(|tree| {
+        let tree = tree.borrow();
+        let witness = labeled(LABEL_ASSETS, tree.witness(path.as_bytes()));
+
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe().ok()?;
+        witness.serialize(&mut serializer).ok()?;
+
+        Some((
+            "IC-Certificate".to_string(),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                BASE64.encode(certificate),
+                BASE64.encode(serializer.into_inner())
+            ),
+        ))
+    })
+}
+
+fn set_entry(tree: &mut RbTree<String, Hash>, path: String, file_hash: Option<&str>) {
+    let hash = file_hash
+        .and_then(|hash| hex::decode(hash).ok())
+        .and_then(|bytes| Hash::try_from(bytes.as_slice()).ok());
+    match hash {
+        Some(hash) => tree.insert(path, hash),
+        None => tree.delete(path.as_bytes()),
+    }
+}
+
+fn publish() {
+    ASSET_HASHES.with(|tree| {
+        let root = labeled_hash(LABEL_ASSETS, &tree.borrow().root_hash());
+        ic_cdk::api::set_certified_data(&root);
+    });
+}
@@ -0,0 +1,77 @@
+// File payloads live in a stable B-tree keyed by `(file_id, index)` so they
+// never touch the Wasm heap and survive upgrades without being serialized.
+use crate::memory::{self, Memory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// File and upload ids are hex encoded SHA-256 digests.
+const MAX_ID_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    file_id: String,
+    index: u32,
+}
+
+impl ChunkKey {
+    fn new(file_id: &str, index: u32) -> Self {
+        assert!(file_id.len() <= MAX_ID_LEN, "chunk key id too long: {}", file_id);
+        Self { file_id: file_id.to_string(), index }
+    }
+}
+
+// Encoded as [id length][id bytes][index big endian], which keeps all chunks
+// of one file next to each other in index order.
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(1 + self.file_id.len() + 4);
+        bytes.push(self.file_id.len() as u8);
+        bytes.extend_from_slice(self.file_id.as_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let file_id = String::from_utf8(bytes[1..1 + len].to_vec()).expect("chunk key id is not utf-8");
+        let index = u32::from_be_bytes(bytes[1 + len..5 + len].try_into().unwrap());
+        Self { file_id, index }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1 + MAX_ID_LEN as u32 + 4,
+        is_fixed_size: false,
+    };
+}
+
+thread_local! {
+    static CHUNKS: RefCell<StableBTreeMap<ChunkKey, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::chunks_memory()));
+}
+
+pub(crate) fn put(file_id: &str, index: u32, data: Vec<u8>) {
+    CHUNKS.with(|c| c.borrow_mut().insert(ChunkKey::new(file_id, index), data));
+}
+
+pub(crate) fn get(file_id: &str, index: u32) -> Option<Vec<u8>> {
+    CHUNKS.with(|c| c.borrow().get(&ChunkKey::new(file_id, index)))
+}
+
+// Drops every chunk stored under `file_id`.
+pub(crate) fn remove_all(file_id: &str) {
+    CHUNKS.with(|c| {
+        let mut chunks = c.borrow_mut();
+        let keys: Vec<ChunkKey> = chunks
+            .keys_range(ChunkKey::new(file_id, 0)..=ChunkKey::new(file_id, u32::MAX))
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+pub(crate) fn clear() {
+    CHUNKS.with(|c| c.borrow_mut().clear_new());
+}
@@ -0,0 +1,154 @@
+// Content encodings a file can be stored in, and `Accept-Encoding`
+// negotiation for the HTTP gateway.
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+pub(crate) const IDENTITY: &str = "identity";
+pub(crate) const GZIP: &str = "gzip";
+// No longer accepted: certified query responses can only carry encodings
+// that v1 `http_assets` verifiers decode before hashing, which brotli is not.
+pub(crate) const BROTLI: &str = "br";
+
+// Preferred first when the client accepts several with the same weight.
+const PREFERENCE: [&str; 2] = [GZIP, IDENTITY];
+
+// Compressing in the canister costs cycles, so only do it for bodies that
+// fit in a single response anyway.
+pub(crate) const MAX_AUTO_COMPRESS_SIZE: u64 = 2 * 1024 * 1024;
+const MIN_AUTO_COMPRESS_SIZE: u64 = 1024;
+
+pub(crate) fn validate(encoding: &str) -> Result<(), String> {
+    match encoding {
+        GZIP => Ok(()),
+        BROTLI => Err("Brotli cannot be served with certified responses, upload gzip instead".to_string()),
+        IDENTITY => Err("The identity encoding is the file itself, upload a new file instead".to_string()),
+        other => Err(format!("Unsupported content encoding: {}", other)),
+    }
+}
+
+pub(crate) fn is_compressible(mime_type: &str, size: u64) -> bool {
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+    (MIN_AUTO_COMPRESS_SIZE..=MAX_AUTO_COMPRESS_SIZE).contains(&size)
+        && (mime_type.starts_with("text/")
+            || mime_type.ends_with("+xml")
+            || mime_type.ends_with("+json")
+            || matches!(mime_type, "application/javascript" | "application/json" | "application/xml" | "application/wasm"))
+}
+
+// Returns the gzip encoding of `data` if it is actually smaller.
+pub(crate) fn gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+// Checks that `encoded` decodes to the `size` bytes hashed `file_hash`, so
+// an uploaded encoding serves the same content as the file. Decodes at most
+// one byte past `size`, however far the body would expand.
+pub(crate) fn check_decodes_to(content_encoding: &str, encoded: &[u8], size: u64, file_hash: &str) -> Result<(), String> {
+    if content_encoding != GZIP {
+        return Err(format!("Unsupported content encoding: {}", content_encoding));
+    }
+    let mut decoded = GzDecoder::new(encoded).take(size.saturating_add(1));
+    let mut hasher = Sha256::new();
+    let decoded_size = io::copy(&mut decoded, &mut hasher)
+        .map_err(|e| format!("Content is not valid {}: {}", content_encoding, e))?;
+    if decoded_size != size || hex::encode(hasher.finalize()) != file_hash {
+        return Err("Content does not decode to the file's current revision".to_string());
+    }
+    Ok(())
+}
+
+// Picks the best encoding the client accepts out of the ones available.
+pub(crate) fn negotiate<'a>(accept_encoding: Option<&str>, available: &[&'a str]) -> &'a str {
+    let Some(accept_encoding) = accept_encoding else {
+        return IDENTITY;
+    };
+
+    let weight = |encoding: &str| -> f32 {
+        let mut wildcard = None;
+        for entry in accept_encoding.split(',') {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if name.eq_ignore_ascii_case(encoding) {
+                return q;
+            }
+            if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+        // identity is acceptable unless explicitly refused.
+        wildcard.unwrap_or(if encoding == IDENTITY { 1.0 } else { 0.0 })
+    };
+
+    let mut best = IDENTITY;
+    let mut best_weight = 0.0;
+    for encoding in PREFERENCE {
+        if !available.contains(&encoding) {
+            continue;
+        }
+        let w = weight(encoding);
+        if w > best_weight {
+            best = encoding;
+            best_weight = w;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [&str; 2] = [IDENTITY, GZIP];
+
+    #[test]
+    fn negotiate_prefers_gzip_at_equal_weight() {
+        assert_eq!(negotiate(Some("gzip, br"), &ALL), GZIP);
+        assert_eq!(negotiate(Some("identity, gzip"), &ALL), GZIP);
+        assert_eq!(negotiate(Some("*"), &ALL), GZIP);
+    }
+
+    #[test]
+    fn negotiate_follows_weights() {
+        assert_eq!(negotiate(Some("gzip;q=0.5, identity;q=0.8"), &ALL), IDENTITY);
+        assert_eq!(negotiate(Some("gzip;q=0, *;q=0.1"), &ALL), IDENTITY);
+        assert_eq!(negotiate(Some("deflate"), &ALL), IDENTITY);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_identity() {
+        assert_eq!(negotiate(None, &ALL), IDENTITY);
+        assert_eq!(negotiate(Some(""), &ALL), IDENTITY);
+        assert_eq!(negotiate(Some("identity;q=0, br"), &ALL), IDENTITY);
+    }
+
+    #[test]
+    fn encodings_must_decode_to_the_file() {
+        let data = b"hello hello hello hello hello hello hello".to_vec();
+        let file_hash = hex::encode(Sha256::digest(&data));
+        let compressed = gzip(&data).unwrap();
+        let size = data.len() as u64;
+        assert!(check_decodes_to(GZIP, &compressed, size, &file_hash).is_ok());
+
+        let other = gzip(b"other other other other other other other").unwrap();
+        assert!(check_decodes_to(GZIP, &other, size, &file_hash).is_err());
+        assert!(check_decodes_to(GZIP, &compressed, size - 1, &file_hash).is_err());
+        assert!(check_decodes_to(GZIP, &data, size, &file_hash).is_err());
+    }
+
+    #[test]
+    fn only_gzip_can_be_uploaded() {
+        assert!(validate(GZIP).is_ok());
+        assert!(validate(BROTLI).is_err());
+        assert!(validate(IDENTITY).is_err());
+    }
+}
@@ -0,0 +1,431 @@
+// HTTP gateway support: maps `/files/{id}` and published paths onto stored
+// files, negotiates the content encoding and streams multi-chunk bodies
+// through a callback. A `?token=` query parameter carrying a signed link
+// grants access in place of the caller's own permissions.
+use crate::acl::Visibility;
+use crate::{certification, encoding, signed_url, DomainError, FileMetadata, State};
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
+
+pub type HeaderField = (String, String);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackToken {
+    pub file_id: String,
+    pub content_encoding: String,
+    pub index: u32,
+    pub signed_token: Option<String>,
+    // Set instead of `signed_token` when the response redeemed a use of a
+    // use-limited link.
+    pub stream_grant: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: ByteBuf,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+candid::define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+impl HttpResponse {
+    fn upgrade() -> Self {
+        Self {
+            status_code: 200,
+            headers: Vec::new(),
+            body: ByteBuf::new(),
+            streaming_strategy: None,
+            upgrade: Some(true),
+        }
+    }
+
+    pub(crate) fn text(status_code: u16, message: &str) -> Self {
+        Self {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+            body: ByteBuf::from(message.as_bytes().to_vec()),
+            streaming_strategy: None,
+            upgrade: None,
+        }
+    }
+}
+
+// Besides the response, returns the token of a use-limited signed link the
+// response consumes a use of. Such links are only served in update calls
+// (`upgraded`); queries ask the gateway to upgrade instead.
+pub(crate) fn handle_request(
+    state: &State,
+    caller: Principal,
+    req: &HttpRequest,
+    upgraded: bool,
+    now: u64,
+) -> (HttpResponse, Option<String>) {
+    let is_head = req.method.eq_ignore_ascii_case("HEAD");
+    if !is_head && !req.method.eq_ignore_ascii_case("GET") {
+        return (HttpResponse::text(405, "Method not allowed"), None);
+    }
+
+    let url = req.url.split('#').next().unwrap_or("/");
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let (certified_path, metadata) = match resolve_path(state, path) {
+        Some((certified_path, metadata)) if metadata.is_active => (certified_path, metadata),
+        _ => return (HttpResponse::text(404, "Not found"), None),
+    };
+
+    let signed_token = query_param(query, "token");
+    let mut redeemed = None;
+    let cache_control = match &signed_token {
+        Some(token) => {
+            let link = match state.signed_file(token, &metadata.id, now) {
+                Ok((link, _)) => link,
+                Err(e) => return (HttpResponse::text(403, &e.to_string()), None),
+            };
+            if link.is_use_limited() && !is_head {
+                if !upgraded {
+                    return (HttpResponse::upgrade(), None);
+                }
+                if link.is_exhausted() {
+                    return (HttpResponse::text(403, "Link has no uses left"), None);
+                }
+                redeemed = Some(token.clone());
+            }
+            "private, no-store"
+        }
+        None => {
+            if state.readable_file(caller, &metadata.id, now).is_err() {
+                return (HttpResponse::text(403, "Forbidden"), None);
+            }
+            // Shared caches may only keep what anyone is allowed to fetch.
+            match state.visibility(metadata) {
+                Visibility::Public => "public",
+                Visibility::Private => "private, no-store",
+            }
+        }
+    };
+
+    let available = metadata.available_encodings();
+    let content_encoding = encoding::negotiate(header(req, "Accept-Encoding"), &available);
+    let (file_hash, size, chunk_count) = match metadata.encoded(content_encoding) {
+        Some(encoded) => encoded,
+        None => return (HttpResponse::text(500, "File content not found"), None),
+    };
+
+    let etag = format!("\"{}\"", file_hash);
+    let mut headers = vec![
+        ("Content-Type".to_string(), metadata.mime_type.clone()),
+        ("Content-Length".to_string(), size.to_string()),
+        ("ETag".to_string(), etag.clone()),
+        ("Cache-Control".to_string(), cache_control.to_string()),
+    ];
+    if available.len() > 1 {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+    if content_encoding != encoding::IDENTITY {
+        headers.push(("Content-Encoding".to_string(), content_encoding.to_string()));
+    }
+
+    if header(req, "If-None-Match").is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag)) {
+        headers.retain(|(name, _)| name != "Content-Length");
+        return (HttpResponse {
+            status_code: 304,
+            headers,
+            body: ByteBuf::new(),
+            streaming_strategy: None,
+            upgrade: None,
+        }, None);
+    }
+
+    if is_head || chunk_count == 0 {
+        return (HttpResponse {
+            status_code: 200,
+            headers,
+            body: ByteBuf::new(),
+            streaming_strategy: None,
+            upgrade: None,
+        }, redeemed);
+    }
+
+    let body = match state.read_blob_chunk(file_hash, 0) {
+        Some(data) => data,
+        None => return (HttpResponse::text(500, "File content not found"), None),
+    };
+
+    if let Some(certificate) = certification::certificate_header(&certified_path) {
+        headers.push(certificate);
+    }
+
+    let first = StreamingCallbackToken {
+        file_id: metadata.id.clone(),
+        content_encoding: content_encoding.to_string(),
+        index: 0,
+        signed_token,
+        stream_grant: None,
+    };
+    let response = HttpResponse {
+        status_code: 200,
+        headers,
+        body: ByteBuf::from(body),
+        streaming_strategy: next_token(&first, chunk_count).map(|token| {
+            StreamingStrategy::Callback {
+                callback: StreamingCallback::new(ic_cdk::id(), "http_request_streaming_callback".to_string()),
+                token,
+            }
+        }),
+        upgrade: None,
+    };
+    (response, redeemed)
+}
+
+// The token of the streaming callback `response` asks the gateway to call.
+fn callback_token(response: &mut HttpResponse) -> Option<&mut StreamingCallbackToken> {
+    match &mut response.streaming_strategy {
+        Some(StreamingStrategy::Callback { token, .. }) => Some(token),
+        None => None,
+    }
+}
+
+// Finishes `response`, which redeemed a use of the link for `token`. The
+// streaming callback cannot spend a grant, so a body that fits in one reply
+// goes out whole and only larger ones stream the rest through a grant.
+pub(crate) fn finish_redeemed(state: &mut State, response: &mut HttpResponse, token: &str, now: u64) {
+    let Some(next) = callback_token(response).map(|next| next.clone()) else {
+        return;
+    };
+    let Some((file_hash, size, chunk_count)) = state.files.get(&next.file_id)
+        .and_then(|metadata| metadata.encoded(&next.content_encoding))
+        .map(|(file_hash, size, chunk_count)| (file_hash.to_string(), size, chunk_count))
+    else {
+        return;
+    };
+
+    if size <= signed_url::MAX_INLINE_BODY {
+        let rest: Option<Vec<Vec<u8>>> = (next.index..chunk_count)
+            .map(|index| state.read_blob_chunk(&file_hash, index))
+            .collect();
+        if let Some(rest) = rest {
+            rest.iter().for_each(|chunk| response.body.extend_from_slice(chunk));
+            response.streaming_strategy = None;
+            return;
+        }
+    }
+
+    let grant_id = state.grant_stream(token, &next.file_id, &next.content_encoding, &file_hash, now);
+    if let Some(next) = callback_token(response) {
+        next.signed_token = None;
+        next.stream_grant = Some(grant_id);
+    }
+}
+
+pub(crate) fn stream_chunk(
+    state: &State,
+    caller: Principal,
+    token: StreamingCallbackToken,
+    now: u64,
+) -> Result<StreamingCallbackHttpResponse, String> {
+    let metadata = match (&token.stream_grant, &token.signed_token) {
+        (Some(grant_id), _) => state.granted_file(grant_id, &token.file_id, &token.content_encoding, token.index, now),
+        (None, Some(signed_token)) => match state.signed_file(signed_token, &token.file_id, now) {
+            Ok((link, _)) if link.is_use_limited() => {
+                Err(DomainError::Forbidden("Links with a use limit stream through a grant".to_string()))
+            }
+            result => result.map(|(_, metadata)| metadata),
+        },
+        (None, None) => state.readable_file(caller, &token.file_id, now),
+    }
+    .map_err(|e| e.to_string())?;
+    let (file_hash, _, chunk_count) = metadata.encoded(&token.content_encoding)
+        .ok_or_else(|| format!("Encoding {} not available", token.content_encoding))?;
+    if token.index >= chunk_count {
+        return Err(format!("Chunk index {} out of range (chunk count {})", token.index, chunk_count));
+    }
+
+    let body = state.read_blob_chunk(file_hash, token.index)
+        .ok_or_else(|| "File content not found".to_string())?;
+
+    Ok(StreamingCallbackHttpResponse {
+        body: ByteBuf::from(body),
+        token: next_token(&token, chunk_count),
+    })
+}
+
+fn next_token(token: &StreamingCallbackToken, chunk_count: u32) -> Option<StreamingCallbackToken> {
+    (token.index + 1 < chunk_count).then(|| StreamingCallbackToken {
+        index: token.index + 1,
+        ..token.clone()
+    })
+}
+
+// `/files/{id}` addresses a file by id, any other path the file published
+// there. Also returns the key the response is certified under.
+fn resolve_path<'a>(state: &'a State, path: &str) -> Option<(String, &'a FileMetadata)> {
+    if let Some(file_id) = path.strip_prefix("/files/") {
+        return state.files.get(file_id)
+            .map(|metadata| (certification::file_path(file_id), metadata));
+    }
+
+    let path = percent_decode(path)?;
+    state.find_by_path(&path).map(|metadata| (path.clone(), metadata))
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_store;
+    use crate::signed_url::SignedLink;
+    use crate::StoredBlob;
+
+    const NOW: u64 = 1_000_000_000;
+    const TOKEN: &str = "link-token";
+
+    // A two chunk file behind a link whose only use has been spent.
+    fn state_with_exhausted_link() -> State {
+        let mut state = State::default();
+        let mut metadata = FileMetadata::for_tests("file-1", "/big.bin", Principal::anonymous(), 0);
+        metadata.size = 6;
+        metadata.chunk_count = 2;
+        metadata.chunk_sizes = vec![3, 3];
+        metadata.file_hash = Some("hash-1".to_string());
+        state.files.insert(metadata.id.clone(), metadata);
+        state.blobs.insert("hash-1".to_string(), StoredBlob {
+            storage_key: "blob-1".to_string(),
+            size: 6,
+            ref_count: 1,
+        });
+        chunk_store::put("blob-1", 0, b"abc".to_vec());
+        chunk_store::put("blob-1", 1, b"def".to_vec());
+        state.signed_links.insert(signed_url::link_id(TOKEN), SignedLink {
+            file_id: "file-1".to_string(),
+            created_by: Principal::anonymous(),
+            created_at: 0,
+            expires_at: NOW * 2,
+            max_uses: Some(1),
+            uses: 1,
+        });
+        state
+    }
+
+    fn callback_token(index: u32, signed_token: Option<&str>, stream_grant: Option<&str>) -> StreamingCallbackToken {
+        StreamingCallbackToken {
+            file_id: "file-1".to_string(),
+            content_encoding: encoding::IDENTITY.to_string(),
+            index,
+            signed_token: signed_token.map(String::from),
+            stream_grant: stream_grant.map(String::from),
+        }
+    }
+
+    #[test]
+    fn exhausted_link_cannot_stream() {
+        let state = state_with_exhausted_link();
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            url: format!("/files/file-1?token={}", TOKEN),
+            headers: Vec::new(),
+            body: ByteBuf::new(),
+        };
+        let (response, redeemed) = handle_request(&state, Principal::anonymous(), &request, true, NOW);
+        assert_eq!(response.status_code, 403);
+        assert!(redeemed.is_none());
+
+        for index in [0, 1] {
+            let token = callback_token(index, Some(TOKEN), None);
+            assert!(stream_chunk(&state, Principal::anonymous(), token, NOW).is_err());
+        }
+    }
+
+    #[test]
+    fn grant_streams_the_rest_of_its_response_only() {
+        let mut state = state_with_exhausted_link();
+        let grant_id = state.grant_stream(TOKEN, "file-1", encoding::IDENTITY, "hash-1", NOW);
+
+        let chunk = stream_chunk(&state, Principal::anonymous(), callback_token(1, None, Some(&grant_id)), NOW).unwrap();
+        assert_eq!(chunk.body.as_slice(), b"def");
+        assert!(chunk.token.is_none());
+
+        let first = callback_token(0, None, Some(&grant_id));
+        assert!(stream_chunk(&state, Principal::anonymous(), first, NOW).is_err());
+        let expired_at = NOW + signed_url::STREAM_GRANT_TTL_SECS * 1_000_000_000;
+        let late = callback_token(1, None, Some(&grant_id));
+        assert!(stream_chunk(&state, Principal::anonymous(), late, expired_at).is_err());
+
+        state.files.get_mut("file-1").unwrap().file_hash = Some("hash-2".to_string());
+        let replaced = callback_token(1, None, Some(&grant_id));
+        assert!(stream_chunk(&state, Principal::anonymous(), replaced, NOW).is_err());
+
+        state.files.get_mut("file-1").unwrap().file_hash = Some("hash-1".to_string());
+        state.signed_links.clear();
+        let revoked = callback_token(1, None, Some(&grant_id));
+        assert!(stream_chunk(&state, Principal::anonymous(), revoked, NOW).is_err());
+    }
+
+    #[test]
+    fn small_redeemed_body_goes_out_whole() {
+        let mut state = state_with_exhausted_link();
+        let mut response = HttpResponse::text(200, "abc");
+        response.streaming_strategy = Some(StreamingStrategy::Callback {
+            callback: StreamingCallback::new(Principal::anonymous(), "http_request_streaming_callback".to_string()),
+            token: callback_token(1, Some(TOKEN), None),
+        });
+
+        finish_redeemed(&mut state, &mut response, TOKEN, NOW);
+        assert_eq!(response.body.as_slice(), b"abcdef");
+        assert!(response.streaming_strategy.is_none());
+        assert!(state.stream_grants.is_empty());
+    }
+}
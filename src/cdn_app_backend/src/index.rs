@@ -0,0 +1,75 @@
+// Secondary indexes over the active files, kept in step with `State::files`
+// so listings and owner lookups don't have to scan every file. Inactive files
+// are never indexed.
+use crate::FileMetadata;
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeBounds;
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct FileIndex {
+    by_owner: BTreeMap<Principal, BTreeSet<String>>,
+    by_uploaded_at: BTreeSet<(u64, String)>,
+    by_hash: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl FileIndex {
+    pub(crate) fn build(files: &HashMap<String, FileMetadata>) -> Self {
+        let mut index = FileIndex::default();
+        for metadata in files.values() {
+            index.insert(metadata);
+        }
+        index
+    }
+
+    pub(crate) fn insert(&mut self, metadata: &FileMetadata) {
+        if !metadata.is_active {
+            return;
+        }
+        self.by_owner.entry(metadata.owner).or_default().insert(metadata.id.clone());
+        self.by_uploaded_at.insert((metadata.uploaded_at, metadata.id.clone()));
+        if let Some(file_hash) = &metadata.file_hash {
+            self.by_hash.entry(file_hash.clone()).or_default().insert(metadata.id.clone());
+        }
+    }
+
+    // Must be called with the metadata as it was indexed, i.e. before the
+    // indexed fields are changed.
+    pub(crate) fn remove(&mut self, metadata: &FileMetadata) {
+        remove_from(&mut self.by_owner, &metadata.owner, &metadata.id);
+        self.by_uploaded_at.remove(&(metadata.uploaded_at, metadata.id.clone()));
+        if let Some(file_hash) = &metadata.file_hash {
+            remove_from(&mut self.by_hash, file_hash, &metadata.id);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = FileIndex::default();
+    }
+
+    pub(crate) fn owned_by(&self, owner: &Principal) -> impl DoubleEndedIterator<Item = &String> {
+        self.by_owner.get(owner).into_iter().flatten()
+    }
+
+    pub(crate) fn with_hash(&self, file_hash: &str) -> impl DoubleEndedIterator<Item = &String> {
+        self.by_hash.get(file_hash).into_iter().flatten()
+    }
+
+    // Files ordered by upload time, then id.
+    pub(crate) fn uploaded(
+        &self,
+        range: impl RangeBounds<(u64, String)>,
+    ) -> impl DoubleEndedIterator<Item = &(u64, String)> {
+        self.by_uploaded_at.range(range)
+    }
+}
+
+fn remove_from<K: Ord>(index: &mut BTreeMap<K, BTreeSet<String>>, key: &K, file_id: &str) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(file_id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
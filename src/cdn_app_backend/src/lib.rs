@@ -1,17 +1,49 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::{api, storage, trap};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::{api, trap};
 // use fully-qualified attributes like #[ic_cdk::query] to avoid needing
 // separate imports for the attribute macros.
 use serde::{Deserialize as SerdeDeserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::ops::Bound;
+
+mod acl;
+mod certification;
+mod chunk_store;
+mod encoding;
+mod http;
+mod index;
+mod listing;
+mod maintenance;
+mod memory;
+mod mime;
+mod paths;
+mod permissions;
+mod quota;
+mod rate_limit;
+mod signed_url;
+mod upgrade;
+mod users;
+
+use acl::{FileAcl, Permission, Visibility};
+use index::FileIndex;
+use quota::{Quota, Usage};
+use rate_limit::RateLimit;
+use signed_url::{SignedLink, SignedUrl, SignedUrlInfo, StreamGrant};
+use users::UserPage;
+use listing::{Cursor, FileFilter, SortBy, SortOrder};
+use maintenance::MaintenanceStatus;
+use http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken};
 
 // Constants
 const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10MB
 const SESSION_DURATION: u64 = 24 * 60 * 60; // 24 hours in seconds
-const ADMIN_PRINCIPAL_ID: &str = "2vxsx-fae"; // Replace with your principal ID
+const MAX_SESSIONS_PER_USER: usize = 16;
+const UPLOAD_SESSION_TIMEOUT: u64 = 60 * 60; // 1 hour in seconds
+const MAX_RESPONSE_SIZE: u64 = 2 * 1024 * 1024; // 2MB, below the query response limit
+const ADMIN_TRANSFER_TTL: u64 = 7 * 24 * 60 * 60; // 7 days in seconds
 
 // Error Types
 #[derive(Debug, Clone, CandidType, Serialize, SerdeDeserialize)]
@@ -51,21 +83,6 @@ impl fmt::Display for DomainError {
     }
 }
 
-#[derive(Debug, Clone, CandidType, Serialize, SerdeDeserialize)]
-pub enum BackendError {
-    NetworkError(String),
-    StorageUnavailable(String),
-    UnexpectedFailure(String),
-    DomainError(String),
-    Unauthorized(String),
-    Forbidden(String),
-    InvalidData(String),
-    ValidationError(String),
-    DuplicateFile,
-    FileNotFound,
-    Other(String),
-}
-
 // User Types
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, SerdeDeserialize, CandidType)]
 pub enum Role {
@@ -74,17 +91,43 @@ pub enum Role {
     Viewer,
 }
 
+// The single record kept per principal: identity, profile and the roles held.
+// Admins can grant roles to principals that never registered; they get a
+// record named after their principal.
 #[derive(Debug, Clone, Serialize, SerdeDeserialize, CandidType)]
 pub struct User {
     pub id: String,
     pub username: String,
-    pub role: Role,
+    #[serde(default)]
+    pub roles: Vec<Role>,
     pub email: Option<String>,
     pub is_active: bool,
 }
 
+impl User {
+    fn new(principal: Principal, username: String, roles: Vec<Role>) -> Self {
+        User {
+            id: principal.to_string(),
+            username,
+            roles,
+            email: None,
+            is_active: true,
+        }
+    }
+
+    // The record of a principal given roles before registering, named after
+    // the principal until `register` fills it in.
+    fn placeholder(principal: Principal) -> Self {
+        User::new(principal, principal.to_string(), Vec::new())
+    }
+
+    fn is_placeholder(&self) -> bool {
+        self.username == self.id
+    }
+}
+
 // File Types
-#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileMetadata {
     pub id: String,
     pub owner: Principal,
@@ -93,24 +136,191 @@ pub struct FileMetadata {
     pub size: u64,
     pub mime_type: String,
     pub uploaded_at: u64,
-    pub roles_allowed: Vec<Role>,
+    #[serde(default)]
+    pub acl: FileAcl,
+    // `None` inherits the visibility of the enclosing directories.
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
     pub chunk_count: u32,
     pub is_active: bool,
+    // When the file was deleted; its metadata is purged by maintenance once
+    // `maintenance::DELETED_FILE_RETENTION` has passed.
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
     pub file_hash: Option<String>,
+    // Byte length of each stored chunk, used to locate byte ranges without
+    // reading the payloads from stable memory.
+    #[serde(default)]
+    pub chunk_sizes: Vec<u64>,
+    // Additional stored representations keyed by content encoding (`gzip`,
+    // `br`). The fields above describe the identity encoding.
+    #[serde(default)]
+    pub encodings: BTreeMap<String, FileEncoding>,
+    // Logical path the file is published under. Re-uploading to the same
+    // path adds a revision instead of creating a new file.
+    #[serde(default)]
+    pub path: String,
+    // Every revision ever published, oldest first. The content fields above
+    // mirror the one numbered `current_revision`.
+    #[serde(default)]
+    pub revisions: Vec<FileRevision>,
+    #[serde(default)]
+    pub current_revision: u32,
+}
+
+impl FileMetadata {
+    fn revision(&self, revision: u32) -> Option<&FileRevision> {
+        self.revisions.iter().find(|r| r.revision == revision)
+    }
+
+    // Makes `revision` the live version of the file.
+    fn apply_revision(&mut self, revision: &FileRevision) {
+        self.current_revision = revision.revision;
+        self.size = revision.size;
+        self.chunk_count = revision.chunk_count;
+        self.chunk_sizes = revision.chunk_sizes.clone();
+        self.file_hash = Some(revision.file_hash.clone());
+        self.mime_type = revision.mime_type.clone();
+        self.encodings = revision.encodings.clone();
+        self.uploaded_at = revision.uploaded_at;
+    }
+
+    // Hash, size and chunk count of the representation stored in `content_encoding`.
+    fn encoded(&self, content_encoding: &str) -> Option<(&str, u64, u32)> {
+        if content_encoding == encoding::IDENTITY {
+            Some((self.file_hash.as_deref()?, self.size, self.chunk_count))
+        } else {
+            self.encodings
+                .get(content_encoding)
+                .map(|e| (e.file_hash.as_str(), e.size, e.chunk_count))
+        }
+    }
+
+    fn available_encodings(&self) -> Vec<&str> {
+        std::iter::once(encoding::IDENTITY)
+            .chain(self.encodings.keys().map(String::as_str))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl FileMetadata {
+    // An active, empty file published at `path`, for tests to fill in.
+    pub(crate) fn for_tests(id: &str, path: &str, owner: Principal, uploaded_at: u64) -> Self {
+        FileMetadata {
+            id: id.to_string(),
+            owner,
+            owner_id: owner.to_string(),
+            filename: path.trim_start_matches('/').to_string(),
+            size: 0,
+            mime_type: "application/octet-stream".to_string(),
+            uploaded_at,
+            acl: FileAcl::default(),
+            visibility: None,
+            chunk_count: 0,
+            is_active: true,
+            deleted_at: None,
+            file_hash: None,
+            chunk_sizes: Vec::new(),
+            encodings: BTreeMap::new(),
+            path: path.to_string(),
+            revisions: Vec::new(),
+            current_revision: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileEncoding {
+    pub size: u64,
+    pub chunk_count: u32,
+    pub file_hash: String,
+    pub chunk_sizes: Vec<u64>,
+}
+
+// Immutable snapshot of one upload to a path. Holds its own references in
+// the blob store, so older revisions stay readable after a newer upload.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileRevision {
+    pub revision: u32,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub chunk_sizes: Vec<u64>,
+    pub file_hash: String,
+    pub mime_type: String,
+    pub encodings: BTreeMap<String, FileEncoding>,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+}
+
+impl FileRevision {
+    fn blob_hashes(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.file_hash).chain(self.encodings.values().map(|e| &e.file_hash))
+    }
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileChunk {
     pub file_id: String,
     pub index: u32,
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
 }
 
+// An in-progress multi-call upload. Chunks may arrive in any order; they are
+// written to the chunk store under the upload id and published once
+// `commit_upload` sees all of them.
+#[derive(Clone, Debug, Serialize, SerdeDeserialize)]
+struct UploadSession {
+    owner: Principal,
+    filename: String,
+    chunk_count: u32,
+    total_size: u64,
+    received_bytes: u64,
+    // Size of every chunk received so far, by index.
+    chunks: BTreeMap<u32, u64>,
+    last_activity: u64,
+    #[serde(default)]
+    content_type: Option<String>,
+    // Set when the upload is an additional encoding of an existing file
+    // rather than a new file: (file id, content encoding).
+    #[serde(default)]
+    target: Option<(String, String)>,
+    // Revision of the target file the encoding is for; committing fails once
+    // another one is current.
+    #[serde(default)]
+    target_revision: u32,
+    // ACL for the file; replaces the current one when the upload is a new
+    // revision.
+    #[serde(default)]
+    acl: Option<FileAcl>,
+}
+
+impl UploadSession {
+    fn is_expired(&self, now: u64) -> bool {
+        self.last_activity + UPLOAD_SESSION_TIMEOUT * 1_000_000_000 < now
+    }
+}
+
+// Content-addressed payload shared by every file with the same `file_hash`.
+// The chunks live in `chunk_store` under `storage_key`, a fresh id assigned
+// by the upload that first stored this content.
+#[derive(Clone, Debug, Serialize, SerdeDeserialize)]
+struct StoredBlob {
+    storage_key: String,
+    size: u64,
+    ref_count: u64,
+}
+
 // State Management
 thread_local! {
     static STATE: std::cell::RefCell<State> = std::cell::RefCell::new(State::default());
 }
 
+// A session is bound to the principal that logged in: calls from that
+// principal act with the roles of its live sessions. `roles` is the scope
+// picked at login; only those the user still holds count, so revoking a role
+// takes effect at once.
 #[derive(Clone, Debug, CandidType, Serialize, SerdeDeserialize)]
 pub struct Session {
     pub user_id: Principal,
@@ -118,388 +328,1946 @@ pub struct Session {
     pub roles: Vec<Role>,
 }
 
+#[derive(Clone, Debug, CandidType, Serialize, SerdeDeserialize)]
+pub struct AdminTransfer {
+    pub from: Principal,
+    pub to: Principal,
+    pub proposed_at: u64,
+    pub expires_at: u64,
+}
+
+// Also taken on upgrade, where the admins are only seeded if the canister
+// has none left.
+#[derive(CandidType, SerdeDeserialize)]
+struct InitArgs {
+    // Principals that start out as admins. Without any, the installer is.
+    admins: Vec<Principal>,
+}
+
+// Everything in here is carried across upgrades by `upgrade::save_state`.
+// File payloads are kept separately in `chunk_store`.
+#[derive(Default, Serialize, SerdeDeserialize)]
 struct State {
     files: HashMap<String, FileMetadata>,
-    chunks: HashMap<String, Vec<FileChunk>>,
-    users: HashMap<Principal, User>,
-    roles: HashMap<Principal, Vec<Role>>,
+    // Keyed by content hash.
+    #[serde(default)]
+    blobs: HashMap<String, StoredBlob>,
+    // Logical path -> id of the active file published there.
+    #[serde(default)]
+    paths: BTreeMap<String, String>,
+    #[serde(default)]
+    index: FileIndex,
+    // Directory path -> visibility inherited by the files below it.
+    #[serde(default)]
+    directory_visibility: BTreeMap<String, Visibility>,
+    // Keyed by the hash of the link token.
+    #[serde(default)]
+    signed_links: HashMap<String, SignedLink>,
+    // Keyed by grant id, see `StreamGrant`.
+    #[serde(default)]
+    stream_grants: HashMap<String, StreamGrant>,
+    // Per-user quotas set by admins, replacing the role defaults.
+    #[serde(default)]
+    quota_overrides: HashMap<Principal, Quota>,
+    uploads: HashMap<String, UploadSession>,
+    users: BTreeMap<Principal, User>,
+    // Roles were kept apart from `users` before v8; only read to merge them
+    // in, see `upgrade`.
+    #[serde(default, rename = "roles", skip_serializing)]
+    legacy_roles: HashMap<Principal, Vec<Role>>,
     sessions: HashMap<String, Session>,
+    // Principal -> ids of their sessions.
+    #[serde(default)]
+    user_sessions: HashMap<Principal, BTreeSet<String>>,
+    #[serde(default)]
+    maintenance: MaintenanceStatus,
+    // Recipient -> admin transfer waiting for them to accept.
+    #[serde(default)]
+    admin_transfers: HashMap<Principal, AdminTransfer>,
     config: Config,
     id_counter: u64,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        Self {
-            files: HashMap::new(),
-            chunks: HashMap::new(),
-            users: HashMap::new(),
-            roles: HashMap::new(),
-            sessions: HashMap::new(),
-            config: Config::default(),
-            id_counter: 0,
+impl State {
+    fn generate_id(&mut self, now: u64) -> String {
+        self.id_counter = self.id_counter.saturating_add(1);
+        let raw = format!("{}:{}", now, self.id_counter);
+        let mut hasher = Sha256::new();
+        hasher.update(raw.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    // Resolves where an upload to `path` goes: the live file at that path if
+    // `caller` may write to it, or `None` when a new file will be created.
+    fn publish_target(&self, caller: Principal, path: &str, now: u64) -> Result<Option<String>, DomainError> {
+        match self.paths.get(path) {
+            Some(file_id) => self.authorized_file(caller, file_id, Permission::Write, now).map(|metadata| Some(metadata.id.clone())),
+            None => paths::check_available(&self.paths, path)
+                .map(|_| None)
+                .map_err(DomainError::InvalidInput),
         }
     }
-}
 
-#[derive(Clone, CandidType, Deserialize)]
-struct Config {
-    max_file_size_bytes: u64,
-    uploads_enabled: bool,
-    cdn_domain: Option<String>,
-    last_updated_nanos: u64,
-}
+    // An ACL passed with a new revision of the existing file `existing`
+    // replaces its ACL, which only the owner or an Admin may do.
+    fn check_upload_acl(&self, caller: Principal, existing: Option<&str>, acl: Option<&FileAcl>, now: u64) -> Result<(), DomainError> {
+        match (existing, acl) {
+            (Some(file_id), Some(_)) => self.acl_manageable_file(caller, file_id, now).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            max_file_size_bytes: 10 * 1024 * 1024, // 10MB
-            uploads_enabled: true,
-            cdn_domain: None,
-            last_updated_nanos: ic_cdk::api::time(),
+    // Publishes content whose blob reference is already held as a new revision
+    // of the file at the filename's path, creating the file under `new_file_id`
+    // if nothing is published there yet. The stored filename is the path
+    // without its leading slash, as `move_file` leaves it. Returns the id of
+    // the file.
+    fn publish(
+        &mut self,
+        new_file_id: String,
+        uploader: Principal,
+        filename: String,
+        mime_type: String,
+        chunk_sizes: Vec<u64>,
+        file_hash: String,
+    ) -> String {
+        let path = paths::normalize(&filename);
+        let filename = path[1..].to_string();
+        let existing = self.paths.get(&path).cloned();
+        let now = api::time();
+        let mut revision = FileRevision {
+            revision: 1,
+            size: chunk_sizes.iter().sum(),
+            chunk_count: chunk_sizes.len() as u32,
+            chunk_sizes,
+            file_hash,
+            mime_type,
+            encodings: BTreeMap::new(),
+            uploaded_by: uploader,
+            uploaded_at: now,
+        };
+
+        let updated = existing.and_then(|file_id| self.update_file(&file_id, |metadata| {
+            revision.revision = metadata.revisions.iter().map(|r| r.revision).max().unwrap_or(0) + 1;
+            metadata.filename = filename.clone();
+            metadata.apply_revision(&revision);
+            metadata.revisions.push(revision.clone());
+            metadata.id.clone()
+        }));
+        let file_id = match updated {
+            Some(file_id) => file_id,
+            None => {
+                let mut metadata = FileMetadata {
+                    id: new_file_id.clone(),
+                    owner: uploader,
+                    owner_id: uploader.to_string(),
+                    filename,
+                    size: 0,
+                    mime_type: String::new(),
+                    uploaded_at: now,
+                    acl: FileAcl::default(),
+                    visibility: None,
+                    chunk_count: 0,
+                    is_active: true,
+                    deleted_at: None,
+                    file_hash: None,
+                    chunk_sizes: Vec::new(),
+                    encodings: BTreeMap::new(),
+                    path: path.clone(),
+                    revisions: Vec::new(),
+                    current_revision: 0,
+                };
+                metadata.apply_revision(&revision);
+                metadata.revisions.push(revision);
+                self.index.insert(&metadata);
+                self.files.insert(new_file_id.clone(), metadata);
+                self.paths.insert(path.clone(), new_file_id.clone());
+                new_file_id
+            }
+        };
+
+        certification::certify_file(self, &file_id, &path);
+        file_id
+    }
+
+    // Applies `update` to a file, keeping the secondary indexes in step.
+    fn update_file<R>(&mut self, file_id: &str, update: impl FnOnce(&mut FileMetadata) -> R) -> Option<R> {
+        let metadata = self.files.get_mut(file_id)?;
+        self.index.remove(metadata);
+        let result = update(metadata);
+        self.index.insert(metadata);
+        Some(result)
+    }
+
+    // Adds a reference to the content with `file_hash`. Returns true if the
+    // content is new and its chunks must be kept under `storage_key`, false if
+    // an identical blob is already stored.
+    fn add_blob_ref(&mut self, file_hash: &str, storage_key: &str, size: u64) -> bool {
+        match self.blobs.get_mut(file_hash) {
+            Some(blob) => {
+                blob.ref_count += 1;
+                false
+            }
+            None => {
+                self.blobs.insert(file_hash.to_string(), StoredBlob {
+                    storage_key: storage_key.to_string(),
+                    size,
+                    ref_count: 1,
+                });
+                true
+            }
         }
     }
-}
 
-// API Types
-type ResultText = Result<String, String>;
-type ResultFile = Result<FileContents, String>;
-type ResultFileInfoVec = Result<Vec<FileInfo>, String>;
-type ResultConfig = Result<Config, String>;
-type ResultRoleVec = Result<Vec<Role>, String>;
-type ResultRoleMap = Result<Vec<(String, Vec<Role>)>, String>;
+    // Drops one reference and frees the chunks once nothing points at them.
+    fn release_blob(&mut self, file_hash: &str) {
+        if let Some(blob) = self.blobs.get_mut(file_hash) {
+            blob.ref_count = blob.ref_count.saturating_sub(1);
+            if blob.ref_count == 0 {
+                chunk_store::remove_all(&blob.storage_key);
+                self.blobs.remove(file_hash);
+            }
+        }
+    }
 
-#[derive(CandidType, Deserialize)]
-struct FileInfo {
-    id: String,
-    filename: String,
-    uploader: String,
-    uploaded_at: u64,
-}
+    fn read_chunk(&self, metadata: &FileMetadata, index: u32) -> Option<Vec<u8>> {
+        self.read_blob_chunk(metadata.file_hash.as_deref()?, index)
+    }
 
-#[derive(CandidType, Deserialize)]
-struct FileContents {
-    filename: String,
-    content: Vec<u8>,
-}
+    // Up to `length` bytes of the file from `offset`, clamped to the end of
+    // the file and to what fits into one response.
+    fn read_range(&self, metadata: &FileMetadata, offset: u64, length: u64) -> Result<Vec<u8>, DomainError> {
+        if offset > metadata.size {
+            return Err(DomainError::InvalidInput(format!("Offset {} beyond end of file ({} bytes)", offset, metadata.size)));
+        }
 
-// Helper Functions
-fn get_caller_id() -> Principal {
-    api::caller()
-}
+        let end = offset + length.min(metadata.size - offset).min(MAX_RESPONSE_SIZE);
+        let mut content = Vec::with_capacity((end - offset) as usize);
+        let mut chunk_start = 0u64;
+        for (index, chunk_size) in metadata.chunk_sizes.iter().enumerate() {
+            let chunk_end = chunk_start + chunk_size;
+            if chunk_end > offset && chunk_start < end {
+                let data = self.read_chunk(metadata, index as u32)
+                    .ok_or_else(|| DomainError::DataCorruption("File content not found".to_string()))?;
+                let from = offset.saturating_sub(chunk_start) as usize;
+                let to = (end.min(chunk_end) - chunk_start) as usize;
+                content.extend_from_slice(&data[from..to]);
+            }
+            if chunk_end >= end {
+                break;
+            }
+            chunk_start = chunk_end;
+        }
 
-fn get_current_time() -> u64 {
-    api::time()
-}
+        Ok(content)
+    }
 
-fn create_session(user_id: Principal, roles: Vec<Role>) -> Session {
-    Session {
-        user_id,
-        expires_at: get_current_time() + SESSION_DURATION * 1_000_000_000, // Convert to nanoseconds
-        roles,
+    fn read_blob_chunk(&self, file_hash: &str, index: u32) -> Option<Vec<u8>> {
+        let blob = self.blobs.get(file_hash)?;
+        chunk_store::get(&blob.storage_key, index)
     }
-}
 
-fn generate_id() -> String {
-    STATE.with(|state| {
-        let mut s = state.borrow_mut();
-        s.id_counter = s.id_counter.saturating_add(1);
-        let now = api::time();
-        let raw = format!("{}:{}", now, s.id_counter);
-        let mut hasher = Sha256::new();
-        hasher.update(raw.as_bytes());
-        hex::encode(hasher.finalize())
-    })
-}
+    // Attaches (or replaces) a compressed representation of a file. Returns
+    // true if the chunks staged under `storage_key` must be kept.
+    fn set_encoding(
+        &mut self,
+        file_id: &str,
+        content_encoding: String,
+        storage_key: &str,
+        chunk_sizes: Vec<u64>,
+        file_hash: String,
+    ) -> bool {
+        let size = chunk_sizes.iter().sum();
+        let keep = self.add_blob_ref(&file_hash, storage_key, size);
+        let encoded = FileEncoding {
+            size,
+            chunk_count: chunk_sizes.len() as u32,
+            file_hash,
+            chunk_sizes,
+        };
+        let replaced = self.files.get_mut(file_id).and_then(|metadata| {
+            let current = metadata.current_revision;
+            let revision = metadata.revisions.iter_mut().find(|r| r.revision == current)?;
+            let previous = revision.encodings.insert(content_encoding.clone(), encoded.clone());
+            metadata.encodings.insert(content_encoding, encoded);
+            previous
+        });
+        if let Some(previous) = replaced {
+            self.release_blob(&previous.file_hash);
+        }
+        keep
+    }
 
-fn validate_session(session_id: &str) -> Option<Session> {
-    STATE.with(|state| {
-        let state = state.borrow();
-        state.sessions.get(session_id).and_then(|session| {
-            if session.expires_at > get_current_time() {
-                Some(session.clone())
-            } else {
-                None
+    // Stores a gzip representation produced by the canister itself for
+    // compressible content types, unless it would take the owner over quota.
+    fn auto_compress(&mut self, file_id: &str, content: &[u8], now: u64) {
+        let owner = match self.files.get(file_id) {
+            Some(m) if self.config.auto_compress && encoding::is_compressible(&m.mime_type, m.size) => m.owner,
+            _ => return,
+        };
+
+        if let Some(compressed) = encoding::gzip(content) {
+            let size = compressed.len() as u64;
+            if self.check_quota(owner, size, 0).is_err() {
+                return;
             }
-        })
-    })
-}
+            let storage_key = self.generate_id(now);
+            let file_hash = hash_data(&compressed);
+            if self.set_encoding(file_id, encoding::GZIP.to_string(), &storage_key, vec![size], file_hash) {
+                chunk_store::put(&storage_key, 0, compressed);
+            }
+        }
+    }
 
-fn check_auth(required_role: Option<Role>) -> Result<Session, String> {
-    let caller = get_caller_id();
-    
-    STATE.with(|state| {
-        let state = state.borrow();
-        
-        // Check if user exists and has roles
-        let user_roles = state.roles.get(&caller)
-            .ok_or_else(|| "User not found".to_string())?;
-        
-        // If a specific role is required, check for it
-        if let Some(role) = required_role {
-            if !user_roles.contains(&role) {
-                return Err(format!("Required role {:?} not found", role));
+    // Looks up a file and checks that `caller` holds `permission` on it.
+    fn authorized_file(&self, caller: Principal, file_id: &str, permission: Permission, now: u64) -> Result<&FileMetadata, DomainError> {
+        let metadata = self.files.get(file_id)
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        if !self.permits(caller, metadata, permission, now) {
+            return Err(DomainError::Forbidden("Access denied".to_string()));
+        }
+        Ok(metadata)
+    }
+
+    // Only the owner or an Admin may change who has access to a file, and like
+    // in `permits` only through a live session.
+    fn acl_manageable_file(&self, caller: Principal, file_id: &str, now: u64) -> Result<&FileMetadata, DomainError> {
+        let roles = self.signed_in_roles(caller, now)?;
+        let metadata = self.files.get(file_id)
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        if metadata.owner != caller && !roles.contains(&Role::Admin) {
+            return Err(DomainError::Forbidden("Only the owner or an Admin can manage access to a file".to_string()));
+        }
+        Ok(metadata)
+    }
+
+    // Looks up an active file and checks that `caller` may read it.
+    fn readable_file(&self, caller: Principal, file_id: &str, now: u64) -> Result<&FileMetadata, DomainError> {
+        let metadata = self.files.get(file_id)
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+
+        if !metadata.is_active {
+            return Err(DomainError::InvalidState("File is not active".to_string()));
+        }
+
+        if !self.permits(caller, metadata, Permission::Read, now) {
+            return Err(DomainError::Forbidden("Access denied".to_string()));
+        }
+
+        Ok(metadata)
+    }
+
+    // Callers without a live session, anonymous ones included, may read
+    // public files and nothing else, whatever the ACL says.
+    fn permits(&self, caller: Principal, metadata: &FileMetadata, permission: Permission, now: u64) -> bool {
+        if permission == Permission::Read && self.visibility(metadata) == Visibility::Public {
+            return true;
+        }
+        let Some(roles) = self.session_roles(caller, now) else {
+            return false;
+        };
+        metadata.owner == caller
+            || roles.contains(&Role::Admin)
+            || metadata.acl.grants(permission, caller, &roles)
+    }
+
+    // Looks up the signed link for `token` and the active file it grants
+    // access to. Exhausted links are returned too; callers that count uses
+    // check that themselves.
+    fn signed_file(&self, token: &str, file_id: &str, now: u64) -> Result<(&SignedLink, &FileMetadata), DomainError> {
+        let link = self.signed_links.get(&signed_url::link_id(token))
+            .filter(|link| link.file_id == file_id)
+            .ok_or_else(|| DomainError::Unauthorized("Invalid or revoked link".to_string()))?;
+        if link.is_expired(now) {
+            return Err(DomainError::Unauthorized("Link has expired".to_string()));
+        }
+        let metadata = self.files.get(file_id)
+            .filter(|metadata| metadata.is_active)
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        Ok((link, metadata))
+    }
+
+    // Counts one use of the link for `token`.
+    fn redeem_signed_link(&mut self, token: &str) -> Result<(), DomainError> {
+        let link = self.signed_links.get_mut(&signed_url::link_id(token))
+            .ok_or_else(|| DomainError::Unauthorized("Invalid or revoked link".to_string()))?;
+        if link.is_exhausted() {
+            return Err(DomainError::LimitExceeded("Link has no uses left".to_string()));
+        }
+        link.uses += 1;
+        Ok(())
+    }
+
+    // Stores a grant for the rest of a response that redeemed a use of the
+    // link for `token` and returns its id. The grant only serves the content
+    // hashed `file_hash` the response started with.
+    fn grant_stream(&mut self, token: &str, file_id: &str, content_encoding: &str, file_hash: &str, now: u64) -> String {
+        self.stream_grants.retain(|_, grant| grant.expires_at > now);
+        let nonce = self.generate_id(now);
+        let grant_id = signed_url::grant_id(token, &nonce);
+        self.stream_grants.insert(grant_id.clone(), StreamGrant {
+            link_id: signed_url::link_id(token),
+            file_id: file_id.to_string(),
+            content_encoding: content_encoding.to_string(),
+            file_hash: file_hash.to_string(),
+            expires_at: now.saturating_add(signed_url::STREAM_GRANT_TTL_SECS * 1_000_000_000),
+        });
+        grant_id
+    }
+
+    // The active file a live grant lets the streaming callback read chunk
+    // `index` of. Chunk 0 went out with the response that redeemed the use, a
+    // new revision or encoding ends the grant and a revoked link takes its
+    // grants with it.
+    fn granted_file(
+        &self,
+        grant_id: &str,
+        file_id: &str,
+        content_encoding: &str,
+        index: u32,
+        now: u64,
+    ) -> Result<&FileMetadata, DomainError> {
+        let grant = self.stream_grants.get(grant_id)
+            .filter(|grant| grant.expires_at > now && grant.file_id == file_id && grant.content_encoding == content_encoding)
+            .filter(|grant| self.signed_links.contains_key(&grant.link_id))
+            .ok_or_else(|| DomainError::Unauthorized("Invalid or expired stream grant".to_string()))?;
+        if index == 0 {
+            return Err(DomainError::Forbidden("The first chunk is only served with the response".to_string()));
+        }
+        self.files.get(&grant.file_id)
+            .filter(|metadata| metadata.is_active)
+            .filter(|metadata| metadata.encoded(content_encoding).is_some_and(|(file_hash, _, _)| file_hash == grant.file_hash))
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))
+    }
+
+    // A per-user override if there is one, otherwise the most generous quota
+    // among the principal's roles. Principals without a role get the Viewer
+    // quota.
+    fn quota_of(&self, principal: Principal) -> Quota {
+        if let Some(quota) = self.quota_overrides.get(&principal) {
+            return *quota;
+        }
+        let viewer = [Role::Viewer];
+        let roles = match self.roles_of(principal) {
+            [] => &viewer,
+            roles => roles,
+        };
+        roles.iter()
+            .map(|role| self.config.role_quotas.iter()
+                .find(|(quota_role, _)| quota_role == role)
+                .map(|(_, quota)| *quota)
+                .unwrap_or_default())
+            .reduce(Quota::max)
+            .unwrap_or_default()
+    }
+
+    // (bytes, files) owned by `principal`.
+    fn usage_of(&self, principal: Principal) -> (u64, u64) {
+        self.index.owned_by(&principal)
+            .filter_map(|file_id| self.files.get(file_id))
+            .fold((0, 0), |(bytes, files), metadata| (bytes + quota::stored_bytes(metadata), files + 1))
+    }
+
+    // Checks that `owner` can take on `added_bytes` more and `added_files`
+    // more files.
+    fn check_quota(&self, owner: Principal, added_bytes: u64, added_files: u64) -> Result<(), DomainError> {
+        let quota = self.quota_of(owner);
+        let (used_bytes, used_files) = self.usage_of(owner);
+        if let Some(max_files) = quota.max_files {
+            if added_files > 0 && used_files + added_files > max_files {
+                return Err(DomainError::LimitExceeded(format!(
+                    "File quota of {} files reached ({} in use)",
+                    max_files, used_files
+                )));
             }
         }
-        
-        Ok(create_session(caller, user_roles.clone()))
-    })
-}
+        if let Some(max_bytes) = quota.max_bytes {
+            if added_bytes > 0 && used_bytes + added_bytes > max_bytes {
+                return Err(DomainError::LimitExceeded(format!(
+                    "Storage quota of {} bytes exceeded: {} in use, {} requested",
+                    max_bytes, used_bytes, added_bytes
+                )));
+            }
+        }
+        Ok(())
+    }
 
-fn check_admin() -> Result<Session, String> {
-    check_auth(Some(Role::Admin))
-}
+    // New content is charged to the owner of the file it is published to, or
+    // to the uploader when it creates a file.
+    fn charged_owner(&self, uploader: Principal, existing: Option<&str>) -> Principal {
+        existing.and_then(|file_id| self.files.get(file_id))
+            .map_or(uploader, |metadata| metadata.owner)
+    }
 
-fn hash_data(data: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    hex::encode(hasher.finalize())
+    fn check_file_size(&self, size: u64) -> Result<(), DomainError> {
+        if size > self.config.max_file_size_bytes {
+            return Err(DomainError::LimitExceeded(format!(
+                "File size of {} bytes exceeds the maximum of {} bytes",
+                size, self.config.max_file_size_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    fn visibility(&self, metadata: &FileMetadata) -> Visibility {
+        metadata.visibility
+            .or_else(|| paths::ancestors(&metadata.path).find_map(|dir| self.directory_visibility.get(dir).copied()))
+            .unwrap_or(Visibility::Private)
+    }
+
+    fn is_admin(&self, caller: Principal, now: u64) -> bool {
+        self.session_roles(caller, now).is_some_and(|roles| roles.contains(&Role::Admin))
+    }
+
+    // Starts a session for `user_id` scoped to `roles`. Expired sessions of
+    // the user are dropped, and so is the oldest one once there are more than
+    // MAX_SESSIONS_PER_USER.
+    fn start_session(&mut self, user_id: Principal, roles: Vec<Role>, now: u64) -> (String, Session) {
+        let expired: Vec<String> = self.sessions_of(user_id)
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        for session_id in expired {
+            self.end_session(&session_id);
+        }
+
+        let session_id = self.generate_id(now);
+        let session = Session {
+            user_id,
+            expires_at: now + SESSION_DURATION * 1_000_000_000, // Convert to nanoseconds
+            roles,
+        };
+        self.sessions.insert(session_id.clone(), session.clone());
+        self.user_sessions.entry(user_id).or_default().insert(session_id.clone());
+
+        if self.user_sessions[&user_id].len() > MAX_SESSIONS_PER_USER {
+            let oldest = self.sessions_of(user_id)
+                .min_by_key(|(_, session)| session.expires_at)
+                .map(|(session_id, _)| session_id.clone());
+            if let Some(oldest) = oldest {
+                self.end_session(&oldest);
+            }
+        }
+        (session_id, session)
+    }
+
+    fn end_session(&mut self, session_id: &str) -> Option<Session> {
+        let session = self.sessions.remove(session_id)?;
+        if let Some(ids) = self.user_sessions.get_mut(&session.user_id) {
+            ids.remove(session_id);
+            if ids.is_empty() {
+                self.user_sessions.remove(&session.user_id);
+            }
+        }
+        Some(session)
+    }
+
+    fn end_sessions_of(&mut self, user_id: Principal) {
+        for session_id in self.user_sessions.remove(&user_id).unwrap_or_default() {
+            self.sessions.remove(&session_id);
+        }
+    }
+
+    fn roles_of(&self, principal: Principal) -> &[Role] {
+        self.users.get(&principal).map(|user| user.roles.as_slice()).unwrap_or_default()
+    }
+
+    // Refuses to let `principal` stop being an active admin if they are the
+    // last one: nobody could change the configuration or roles any more.
+    fn check_not_last_admin(&self, principal: Principal) -> Result<(), DomainError> {
+        let is_active_admin = |user: &User| user.is_active && user.roles.contains(&Role::Admin);
+        let others = self.users.iter()
+            .filter(|(other, user)| **other != principal && is_active_admin(user))
+            .count();
+        if others == 0 && self.users.get(&principal).is_some_and(is_active_admin) {
+            return Err(DomainError::InvalidState("Cannot remove the last admin".to_string()));
+        }
+        Ok(())
+    }
+
+    fn has_active_admin(&self) -> bool {
+        self.users.values().any(|user| user.is_active && user.roles.contains(&Role::Admin))
+    }
+
+    // Creates the record of `caller` with the default role, or fills in the
+    // placeholder left by granting them roles before they registered.
+    fn register(&mut self, caller: Principal, username: String, email: Option<String>) -> Result<User, DomainError> {
+        let user = self.users.entry(caller).or_insert_with(|| User::placeholder(caller));
+        if !user.is_placeholder() {
+            return Err(DomainError::DuplicateEntry("User already registered".to_string()));
+        }
+        user.username = username;
+        user.email = email;
+        if user.roles.is_empty() {
+            user.roles.push(Role::Viewer);
+        }
+        Ok(user.clone())
+    }
+
+    // Makes each of `admins` an active admin, keeping the records they have.
+    fn seed_admins(&mut self, admins: Vec<Principal>) {
+        for admin in admins {
+            let user = self.users.entry(admin)
+                .or_insert_with(|| User::placeholder(admin));
+            user.is_active = true;
+            if !user.roles.contains(&Role::Admin) {
+                user.roles.push(Role::Admin);
+            }
+        }
+    }
+
+    fn check_active(&self, caller: Principal) -> Result<(), DomainError> {
+        if self.users.get(&caller).is_some_and(|user| !user.is_active) {
+            return Err(DomainError::Forbidden("Account is deactivated".to_string()));
+        }
+        Ok(())
+    }
+
+    fn sessions_of(&self, user_id: Principal) -> impl Iterator<Item = (&String, &Session)> {
+        self.user_sessions.get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|session_id| self.sessions.get_key_value(session_id))
+    }
+
+    // `session` as it stands now: `None` once it has expired, otherwise with
+    // its scope narrowed to the roles the user still holds.
+    fn live_session(&self, session: &Session, now: u64) -> Option<Session> {
+        if session.expires_at <= now {
+            return None;
+        }
+        let held = self.roles_of(session.user_id);
+        Some(Session {
+            roles: session.roles.iter().filter(|role| held.contains(role)).cloned().collect(),
+            ..session.clone()
+        })
+    }
+
+    // `session_roles` for endpoints that refuse callers without a session,
+    // and deactivated users whatever they hold.
+    fn signed_in_roles(&self, caller: Principal, now: u64) -> Result<Vec<Role>, DomainError> {
+        self.check_active(caller)?;
+        self.session_roles(caller, now)
+            .ok_or_else(|| DomainError::Unauthorized("An active session is required, call login first".to_string()))
+    }
+
+    // The roles `caller` acts with, or `None` when they have no live
+    // session. Calls do not say which session they belong to, so these are
+    // the roles all live sessions carry: a session scoped down to fewer roles
+    // narrows every call until it ends.
+    fn session_roles(&self, caller: Principal, now: u64) -> Option<Vec<Role>> {
+        self.sessions_of(caller)
+            .filter_map(|(_, session)| self.live_session(session, now))
+            .map(|session| session.roles)
+            .reduce(|mut roles, scope| {
+                roles.retain(|role| scope.contains(role));
+                roles
+            })
+    }
+
+    // Moves the file published at `from` to the unused path `to`. Revisions
+    // and the file id stay the same.
+    fn move_file(&mut self, caller: Principal, from: &str, to: String, now: u64) -> Result<&FileMetadata, DomainError> {
+        let file_id = self.paths.get(from).cloned()
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        self.authorized_file(caller, &file_id, Permission::Write, now)?;
+
+        if to != from {
+            if self.paths.contains_key(&to) {
+                return Err(DomainError::DuplicateEntry(format!("A file already exists at {}", to)));
+            }
+            paths::check_available(&self.paths, &to).map_err(DomainError::InvalidInput)?;
+
+            self.paths.remove(from);
+            self.paths.insert(to.clone(), file_id.clone());
+            let metadata = self.files.get_mut(&file_id).unwrap();
+            metadata.filename = to[1..].to_string();
+            metadata.path = to.clone();
+            certification::certify_file(self, &file_id, from);
+            certification::certify_file(self, &file_id, &to);
+        }
+        Ok(&self.files[&file_id])
+    }
+
+    // Deletes a file `caller` may delete, releasing the blobs of all its
+    // revisions and the links to it. Returns the path it was published at,
+    // for the caller to certify again.
+    fn delete_file(&mut self, caller: Principal, file_id: &str, now: u64) -> Result<String, DomainError> {
+        self.authorized_file(caller, file_id, Permission::Delete, now)?;
+        let (was_active, path, released) = self.update_file(file_id, |metadata| {
+            let was_active = std::mem::replace(&mut metadata.is_active, false);
+            if was_active {
+                metadata.deleted_at = Some(now);
+            }
+            let released: Vec<String> = metadata.revisions.iter()
+                .filter(|_| was_active)
+                .flat_map(|revision| revision.blob_hashes().cloned())
+                .collect();
+            (was_active, metadata.path.clone(), released)
+        })
+        .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        if was_active {
+            for file_hash in released {
+                self.release_blob(&file_hash);
+            }
+            self.paths.remove(&path);
+            self.signed_links.retain(|_, link| link.file_id != file_id);
+            self.stream_grants.retain(|_, grant| grant.file_id != file_id);
+        }
+        Ok(path)
+    }
+
+    // Active file published at `path`.
+    fn find_by_path(&self, path: &str) -> Option<&FileMetadata> {
+        self.paths.get(path)
+            .and_then(|file_id| self.files.get(file_id))
+            .filter(|metadata| metadata.is_active)
+    }
+
+    // Stages chunk `index` of the upload `upload_id`. A re-sent chunk replaces
+    // the previous payload for that index.
+    fn put_chunk(&mut self, caller: Principal, upload_id: &str, index: u32, data: Vec<u8>, now: u64) -> Result<(), DomainError> {
+        let max_file_size = self.config.max_file_size_bytes;
+        let upload = match self.uploads.get_mut(upload_id) {
+            Some(upload) if !upload.is_expired(now) => upload,
+            _ => return Err(DomainError::NotFound("Upload session not found or expired".to_string())),
+        };
+
+        if upload.owner != caller {
+            return Err(DomainError::Forbidden("Access denied".to_string()));
+        }
+
+        if index >= upload.chunk_count {
+            return Err(DomainError::InvalidInput(format!("Chunk index {} out of range (chunk count {})", index, upload.chunk_count)));
+        }
+
+        let replaced = upload.chunks.get(&index).copied().unwrap_or(0);
+        let received = upload.received_bytes - replaced + data.len() as u64;
+        if received > upload.total_size || received > max_file_size {
+            return Err(DomainError::LimitExceeded("File size exceeds maximum allowed".to_string()));
+        }
+
+        upload.received_bytes = received;
+        upload.chunks.insert(index, data.len() as u64);
+        upload.last_activity = now;
+        chunk_store::put(upload_id, index, data);
+        Ok(())
+    }
+
+    // The upload `upload_id` of `caller`, once every chunk and every byte
+    // announced by `begin_upload` has arrived.
+    fn completed_upload(&self, caller: Principal, upload_id: &str, now: u64) -> Result<&UploadSession, DomainError> {
+        let upload = self.uploads.get(upload_id)
+            .filter(|upload| !upload.is_expired(now))
+            .ok_or_else(|| DomainError::NotFound("Upload session not found or expired".to_string()))?;
+        if upload.owner != caller {
+            return Err(DomainError::Forbidden("Access denied".to_string()));
+        }
+        if upload.chunks.len() as u32 != upload.chunk_count {
+            return Err(DomainError::InvalidState(format!(
+                "Upload incomplete: received {} of {} chunks",
+                upload.chunks.len(),
+                upload.chunk_count
+            )));
+        }
+        if upload.received_bytes != upload.total_size {
+            return Err(DomainError::InvalidState(format!(
+                "Upload size mismatch: received {} of {} bytes",
+                upload.received_bytes, upload.total_size
+            )));
+        }
+        Ok(upload)
+    }
+
+    // The purge_* methods return how many entries they dropped.
+    fn purge_expired_uploads(&mut self, now: u64) -> u64 {
+        let before = self.uploads.len();
+        self.uploads.retain(|upload_id, upload| {
+            if upload.is_expired(now) {
+                chunk_store::remove_all(upload_id);
+                false
+            } else {
+                true
+            }
+        });
+        (before - self.uploads.len()) as u64
+    }
+
+    fn purge_expired_sessions(&mut self, now: u64) -> u64 {
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        for session_id in &expired {
+            self.end_session(session_id);
+        }
+        expired.len() as u64
+    }
+
+    // Deleted files have already released their content; this drops the
+    // metadata left behind. Files deleted before deletion times were recorded
+    // count from their upload time.
+    fn purge_deleted_files(&mut self, now: u64) -> u64 {
+        let retention = maintenance::DELETED_FILE_RETENTION * 1_000_000_000;
+        let before = self.files.len();
+        self.files.retain(|_, metadata| {
+            metadata.is_active
+                || metadata.deleted_at.unwrap_or(metadata.uploaded_at).saturating_add(retention) > now
+        });
+        (before - self.files.len()) as u64
+    }
+
+    // Drops the expired stream grants too, without counting them.
+    fn purge_expired_signed_links(&mut self, now: u64) -> u64 {
+        self.stream_grants.retain(|_, grant| grant.expires_at > now);
+        let before = self.signed_links.len();
+        self.signed_links.retain(|_, link| !link.is_expired(now));
+        (before - self.signed_links.len()) as u64
+    }
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+struct Config {
+    max_file_size_bytes: u64,
+    uploads_enabled: bool,
+    cdn_domain: Option<String>,
+    last_updated_nanos: u64,
+    // Store a canister-generated gzip encoding next to compressible uploads.
+    #[serde(default = "default_auto_compress")]
+    auto_compress: bool,
+    // File extension (lowercase, without the dot) -> MIME type used when an
+    // upload does not name its content type.
+    #[serde(default = "mime::default_table")]
+    mime_types: BTreeMap<String, String>,
+    // Storage quota of each role, unlimited for roles not listed.
+    #[serde(default = "quota::default_role_quotas")]
+    role_quotas: Vec<(Role, Quota)>,
+    // Calls per caller to each listed update method; unlisted methods are
+    // not limited.
+    #[serde(default = "rate_limit::default_limits")]
+    rate_limits: Vec<(String, RateLimit)>,
+}
+
+impl Config {
+    fn rate_limit(&self, method: &str) -> Option<RateLimit> {
+        self.rate_limits.iter()
+            .find(|(limited, _)| limited == method)
+            .map(|(_, limit)| *limit)
+    }
+}
+
+fn default_auto_compress() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_file_size_bytes: MAX_FILE_SIZE,
+            uploads_enabled: true,
+            cdn_domain: None,
+            // Stamped by `init` and `reset_config`.
+            last_updated_nanos: 0,
+            auto_compress: default_auto_compress(),
+            mime_types: mime::default_table(),
+            role_quotas: quota::default_role_quotas(),
+            rate_limits: rate_limit::default_limits(),
+        }
+    }
+}
+
+// API Types
+type ResultText = Result<String, DomainError>;
+type ResultUploadId = Result<String, DomainError>;
+type ResultFile = Result<FileContents, DomainError>;
+type ResultFileInfo = Result<FileInfo, DomainError>;
+type ResultFileInfoVec = Result<Vec<FileInfo>, DomainError>;
+type ResultChunk = Result<FileChunk, DomainError>;
+type ResultRevisionVec = Result<Vec<RevisionInfo>, DomainError>;
+type ResultDirectoryListing = Result<DirectoryListing, DomainError>;
+type ResultFilePage = Result<FilePage, DomainError>;
+type ResultFileAcl = Result<FileAcl, DomainError>;
+type ResultSignedUrl = Result<SignedUrl, DomainError>;
+type ResultSignedUrlInfoVec = Result<Vec<SignedUrlInfo>, DomainError>;
+type ResultBytes = Result<Vec<u8>, DomainError>;
+type ResultConfig = Result<Config, DomainError>;
+type ResultRoleVec = Result<Vec<Role>, DomainError>;
+type ResultRoleMap = Result<Vec<(String, Vec<Role>)>, DomainError>;
+
+#[derive(CandidType, Deserialize)]
+struct FileInfo {
+    id: String,
+    filename: String,
+    uploader: String,
+    uploaded_at: u64,
+    size: u64,
+    chunk_count: u32,
+    file_hash: Option<String>,
+    mime_type: String,
+    encodings: Vec<String>,
+    path: String,
+    revision: u32,
+    visibility: Option<Visibility>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct FilePage {
+    files: Vec<FileInfo>,
+    next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DirectoryListing {
+    prefix: String,
+    folders: Vec<String>,
+    files: Vec<FileInfo>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RevisionInfo {
+    revision: u32,
+    size: u64,
+    file_hash: String,
+    mime_type: String,
+    uploaded_by: String,
+    uploaded_at: u64,
+    is_current: bool,
+}
+
+impl From<&FileMetadata> for FileInfo {
+    fn from(metadata: &FileMetadata) -> Self {
+        Self {
+            id: metadata.id.clone(),
+            filename: metadata.filename.clone(),
+            uploader: metadata.owner_id.clone(),
+            uploaded_at: metadata.uploaded_at,
+            size: metadata.size,
+            chunk_count: metadata.chunk_count,
+            file_hash: metadata.file_hash.clone(),
+            mime_type: metadata.mime_type.clone(),
+            encodings: metadata.available_encodings().into_iter().map(String::from).collect(),
+            path: metadata.path.clone(),
+            revision: metadata.current_revision,
+            visibility: metadata.visibility,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct FileContents {
+    filename: String,
+    content: Vec<u8>,
+}
+
+// Helper Functions
+fn get_caller_id() -> Principal {
+    api::caller()
+}
+
+fn get_current_time() -> u64 {
+    api::time()
+}
+
+// Admin-only queries are not in the permission matrix; they need a live
+// session holding Admin.
+fn check_admin() -> Result<Principal, DomainError> {
+    let caller = get_caller_id();
+    let roles = STATE.with(|state| state.borrow().signed_in_roles(caller, api::time()))?;
+    if !roles.contains(&Role::Admin) {
+        return Err(DomainError::Forbidden("Required role Admin not found".to_string()));
+    }
+    Ok(caller)
+}
+
+// Checks the caller against the permission matrix for the update method
+// `method`, using the roles of their live sessions, then uses up one of their
+// calls to it. Deactivated users are refused whatever the method.
+fn authorize(method: &str) -> Result<Principal, DomainError> {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.check_active(caller)?;
+        permissions::check(method, caller, state.session_roles(caller, api::time()).as_deref())
+    })?;
+    check_rate_limit(caller, method)?;
+    Ok(caller)
+}
+
+// Uses up one of the caller's calls to `method` if `Config` limits it.
+fn check_rate_limit(caller: Principal, method: &str) -> Result<(), DomainError> {
+    let limit = STATE.with(|state| state.borrow().config.rate_limit(method));
+    match limit {
+        Some(limit) if !rate_limit::take(caller, method, &limit, api::time()) => Err(DomainError::LimitExceeded(
+            format!("Rate limit for {} exceeded, try again later", method),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn hash_data(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+// The caller's session `session_id` with the roles it currently carries.
+#[ic_cdk::query(name = "verify_session")]
+fn verify_session(session_id: String) -> Result<Session, DomainError> {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.sessions.get(&session_id)
+            .filter(|session| session.user_id == caller)
+            .and_then(|session| state.live_session(session, api::time()))
+            .ok_or_else(|| DomainError::Unauthorized("Invalid or expired session".to_string()))
+    })
+}
+
+// Starts a session for the caller. `scope` limits it to some of the caller's
+// roles, and while it lives the caller acts with no others; by default it
+// carries all of them. Roles granted later need a new session.
+#[ic_cdk::update(name = "login")]
+fn login(scope: Option<Vec<Role>>) -> Result<(String, Session), DomainError> {
+    let caller = authorize("login")?;
+    
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        
+        let held = match state.users.get(&caller) {
+            None => return Err(DomainError::NotFound("User not registered".to_string())),
+            Some(user) if user.roles.is_empty() => return Err(DomainError::Forbidden("No roles held".to_string())),
+            Some(user) => user.roles.clone(),
+        };
+        let roles = match scope {
+            Some(scope) => {
+                if let Some(role) = scope.iter().find(|role| !held.contains(role)) {
+                    return Err(DomainError::InvalidRole(format!("Role {:?} is not held by the caller", role)));
+                }
+                if scope.is_empty() {
+                    return Err(DomainError::InvalidRole("A session needs at least one role".to_string()));
+                }
+                scope
+            }
+            None => held,
+        };
+        
+        Ok(state.start_session(caller, roles, api::time()))
+    })
+}
+
+#[ic_cdk::update(name = "register")]
+fn register(username: String, email: Option<String>) -> Result<User, DomainError> {
+    let caller = authorize("register")?;
+    users::validate_username(&username).map_err(DomainError::InvalidInput)?;
+    if let Some(email) = &email {
+        users::validate_email(email).map_err(DomainError::InvalidInput)?;
+    }
+    
+    STATE.with(|state| state.borrow_mut().register(caller, username, email))
+}
+
+#[ic_cdk::query(name = "get_my_profile")]
+fn get_my_profile() -> Result<User, DomainError> {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.check_active(caller)?;
+        state.users.get(&caller)
+            .cloned()
+            .ok_or_else(|| DomainError::NotFound("User not registered".to_string()))
+    })
+}
+
+// Fields left out stay as they are; `Some(None)` clears the email.
+#[ic_cdk::update(name = "update_profile")]
+fn update_profile(username: Option<String>, email: Option<Option<String>>) -> Result<User, DomainError> {
+    let caller = authorize("update_profile")?;
+    if let Some(username) = &username {
+        users::validate_username(username).map_err(DomainError::InvalidInput)?;
+    }
+    if let Some(Some(email)) = &email {
+        users::validate_email(email).map_err(DomainError::InvalidInput)?;
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let user = state.users.get_mut(&caller)
+            .ok_or_else(|| DomainError::NotFound("User not registered".to_string()))?;
+        if let Some(username) = username {
+            user.username = username;
+        }
+        if let Some(email) = email {
+            user.email = email;
+        }
+        Ok(user.clone())
+    })
+}
+
+// Users ordered by principal. `cursor` is the `next_cursor` of the previous
+// page.
+#[ic_cdk::query(name = "list_users")]
+fn list_users(cursor: Option<String>, limit: Option<u32>) -> Result<UserPage, DomainError> {
+    check_admin()?;
+    let after = cursor.as_deref()
+        .map(Principal::from_text)
+        .transpose()
+        .map_err(|_| DomainError::InvalidInput("Invalid cursor".to_string()))?;
+    let limit = limit.unwrap_or(listing::DEFAULT_PAGE_SIZE).clamp(1, listing::MAX_PAGE_SIZE) as usize;
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut page = state.users.range((start, Bound::Unbounded)).map(|(_, user)| user.clone());
+        let users: Vec<User> = page.by_ref().take(limit).collect();
+        let next_cursor = match page.next() {
+            Some(_) => users.last().map(|user| user.id.clone()),
+            None => None,
+        };
+        Ok(UserPage { users, next_cursor })
+    })
+}
+
+// Blocks the user and ends all of their sessions.
+#[ic_cdk::update(name = "deactivate_user")]
+fn deactivate_user(principal_text: String) -> Result<User, DomainError> {
+    let caller = authorize("deactivate_user")?;
+    let principal = Principal::from_text(principal_text)
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
+    if principal == caller {
+        return Err(DomainError::InvalidInput("Admins cannot deactivate themselves".to_string()));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.check_not_last_admin(principal)?;
+        let user = state.users.get_mut(&principal)
+            .ok_or_else(|| DomainError::NotFound("User not found".to_string()))?;
+        user.is_active = false;
+        let user = user.clone();
+        state.end_sessions_of(principal);
+        Ok(user)
+    })
+}
+
+// Lifts the block; the user has to log in again.
+#[ic_cdk::update(name = "reactivate_user")]
+fn reactivate_user(principal_text: String) -> Result<User, DomainError> {
+    authorize("reactivate_user")?;
+    let principal = Principal::from_text(principal_text)
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let user = state.users.get_mut(&principal)
+            .ok_or_else(|| DomainError::NotFound("User not found".to_string()))?;
+        user.is_active = true;
+        Ok(user.clone())
+    })
+}
+
+#[ic_cdk::update(name = "logout")]
+fn logout(session_id: String) -> Result<(), DomainError> {
+    let caller = authorize("logout")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.sessions.get(&session_id) {
+            Some(session) if session.user_id == caller => {
+                state.end_session(&session_id);
+                Ok(())
+            }
+            _ => Err(DomainError::NotFound("Session not found".to_string())),
+        }
+    })
+}
+
+// Canister API Implementation
+// The admins named in `args`, or the caller installing or upgrading the
+// canister.
+fn initial_admins(hook: &str, args: Option<InitArgs>) -> Vec<Principal> {
+    let mut admins = args.map(|args| args.admins).unwrap_or_default();
+    if admins.is_empty() {
+        admins.push(get_caller_id());
+    }
+    if admins.contains(&Principal::anonymous()) {
+        trap(&format!("{}: the anonymous principal cannot be an admin", hook));
+    }
+    admins
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let admins = initial_admins("init", args);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config.last_updated_nanos = api::time();
+        state.seed_admins(admins);
+    });
+    maintenance::start();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| {
+        if let Err(e) = upgrade::save_state(&state.borrow()) {
+            trap(&format!("pre_upgrade: {}", e));
+        }
+    });
+}
+
+// Without an active admin nobody could grant roles or change the
+// configuration again, so a canister left without one, e.g. by an upgrade
+// from a release that kept no snapshot, gets the admins from `args`.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    match upgrade::load_state() {
+        Ok(Some(restored)) => STATE.with(|state| {
+            *state.borrow_mut() = restored;
+            certification::rebuild(&state.borrow());
+        }),
+        Ok(None) => ic_cdk::println!("post_upgrade: no state snapshot found, starting empty"),
+        Err(e) => trap(&format!("post_upgrade: {}", e)),
+    }
+    let admins = initial_admins("post_upgrade", args);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.has_active_admin() {
+            state.seed_admins(admins);
+        }
+    });
+    maintenance::start();
+}
+
+#[ic_cdk::query(name = "get_maintenance_status")]
+fn get_maintenance_status() -> Result<MaintenanceStatus, DomainError> {
+    check_admin()?;
+    STATE.with(|state| Ok(state.borrow().maintenance.clone()))
+}
+
+#[ic_cdk::query(name = "health")]
+fn health() -> String {
+    "healthy".to_string()
+}
+
+#[ic_cdk::query(name = "stats")]
+fn stats() -> String {
+    STATE.with(|state| {
+        let state = state.borrow();
+        format!(
+            "Files: {}, Users: {}, Active uploads enabled: {}",
+            state.files.len(),
+            state.users.len(),
+            state.config.uploads_enabled
+        )
+    })
+}
+
+#[ic_cdk::update(name = "upload_file")]
+fn upload_file(filename: String, content: Vec<u8>, content_type: Option<String>, acl: Option<FileAcl>) -> ResultUploadId {
+    let caller = authorize("upload_file")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.config.uploads_enabled {
+            return Err(DomainError::ServiceUnavailable("Uploads are currently disabled".to_string()));
+        }
+        
+        state.check_file_size(content.len() as u64)?;
+
+        if let Some(content_type) = &content_type {
+            mime::validate(content_type).map_err(DomainError::InvalidInput)?;
+        }
+
+        if let Some(acl) = &acl {
+            acl.validate().map_err(DomainError::InvalidInput)?;
+        }
+
+        let existing = state.publish_target(caller, &paths::validate(&filename).map_err(DomainError::InvalidInput)?, api::time())?;
+        state.check_upload_acl(caller, existing.as_deref(), acl.as_ref(), api::time())?;
+        state.check_quota(state.charged_owner(caller, existing.as_deref()), content.len() as u64, existing.is_none() as u64)?;
+
+        // Doubles as the storage key of the content and the id of a new file.
+        let now = get_current_time();
+        let new_id = state.generate_id(now);
+        let file_hash = hash_data(&content);
+        let size = content.len() as u64;
+        let mime_type = mime::resolve(content_type.as_deref(), &filename, &content, &state.config.mime_types);
+        let is_new_content = state.add_blob_ref(&file_hash, &new_id, size);
+        let file_id = state.publish(new_id.clone(), caller, filename, mime_type, vec![size], file_hash);
+        if let Some(acl) = acl {
+            state.files.get_mut(&file_id).unwrap().acl = acl;
+        }
+        state.auto_compress(&file_id, &content, now);
+        if is_new_content {
+            chunk_store::put(&new_id, 0, content);
+        }
+
+        Ok(file_id)
+    })
+}
+
+#[ic_cdk::update(name = "begin_upload")]
+fn begin_upload(
+    filename: String,
+    chunk_count: u32,
+    total_size: u64,
+    content_type: Option<String>,
+    acl: Option<FileAcl>,
+) -> ResultUploadId {
+    let caller = authorize("begin_upload")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.config.uploads_enabled {
+            return Err(DomainError::ServiceUnavailable("Uploads are currently disabled".to_string()));
+        }
+
+        if chunk_count == 0 {
+            return Err(DomainError::InvalidInput("Chunk count must be at least 1".to_string()));
+        }
+
+        state.check_file_size(total_size)?;
+
+        if let Some(content_type) = &content_type {
+            mime::validate(content_type).map_err(DomainError::InvalidInput)?;
+        }
+
+        if let Some(acl) = &acl {
+            acl.validate().map_err(DomainError::InvalidInput)?;
+        }
+
+        let existing = state.publish_target(caller, &paths::validate(&filename).map_err(DomainError::InvalidInput)?, api::time())?;
+        state.check_upload_acl(caller, existing.as_deref(), acl.as_ref(), api::time())?;
+        state.check_quota(state.charged_owner(caller, existing.as_deref()), total_size, existing.is_none() as u64)?;
+
+        let now = get_current_time();
+        state.purge_expired_uploads(now);
+
+        let upload_id = state.generate_id(now);
+        state.uploads.insert(upload_id.clone(), UploadSession {
+            owner: caller,
+            filename,
+            chunk_count,
+            total_size,
+            received_bytes: 0,
+            chunks: BTreeMap::new(),
+            last_activity: now,
+            content_type,
+            target: None,
+            target_revision: 0,
+            acl,
+        });
+
+        Ok(upload_id)
+    })
+}
+
+#[ic_cdk::update(name = "put_chunk")]
+fn put_chunk(upload_id: String, index: u32, data: Vec<u8>) -> Result<(), DomainError> {
+    let caller = authorize("put_chunk")?;
+    STATE.with(|state| state.borrow_mut().put_chunk(caller, &upload_id, index, data, get_current_time()))
+}
+
+#[ic_cdk::update(name = "commit_upload")]
+fn commit_upload(upload_id: String) -> ResultUploadId {
+    let caller = authorize("commit_upload")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let now = get_current_time();
+
+        let chunk_count = state.completed_upload(caller, &upload_id, now)?.chunk_count;
+
+        let (total_size, target, target_revision, path) = {
+            let upload = &state.uploads[&upload_id];
+            (upload.total_size, upload.target.clone(), upload.target_revision, paths::normalize(&upload.filename))
+        };
+        let existing = match &target {
+            Some((file_id, _)) => {
+                let metadata = state.authorized_file(caller, file_id, Permission::Write, now)?;
+                if !metadata.is_active {
+                    return Err(DomainError::InvalidState("File is not active".to_string()));
+                }
+                if metadata.current_revision != target_revision {
+                    return Err(DomainError::InvalidState(
+                        "A new revision was published since the upload began".to_string(),
+                    ));
+                }
+                Some(file_id.clone())
+            }
+            None => state.publish_target(caller, &path, now)?,
+        };
+        state.check_upload_acl(caller, existing.as_deref(), state.uploads[&upload_id].acl.as_ref(), now)?;
+        state.check_quota(state.charged_owner(caller, existing.as_deref()), total_size, existing.is_none() as u64)?;
+
+        // Encodings are checked against the file, and bodies small enough are
+        // compressed here: keep the whole body around while hashing.
+        let keep_content = target.is_some() || total_size <= encoding::MAX_AUTO_COMPRESS_SIZE;
+        let mut hasher = Sha256::new();
+        let mut content = Vec::new();
+        let mut first_chunk = Vec::new();
+        for index in 0..chunk_count {
+            let data = chunk_store::get(&upload_id, index)
+                .ok_or_else(|| DomainError::DataCorruption(format!("Chunk {} missing from storage", index)))?;
+            hasher.update(&data);
+            if keep_content {
+                content.extend_from_slice(&data);
+            }
+            if index == 0 {
+                first_chunk = data;
+            }
+        }
+        let file_hash = hex::encode(hasher.finalize());
+        if let Some((file_id, content_encoding)) = &target {
+            let metadata = &state.files[file_id];
+            encoding::check_decodes_to(content_encoding, &content, metadata.size, metadata.file_hash.as_deref().unwrap_or_default())
+                .map_err(DomainError::InvalidData)?;
+        }
+
+        let upload = state.uploads.remove(&upload_id).unwrap();
+        // BTreeMap iteration yields the sizes in index order regardless of arrival order.
+        let chunk_sizes: Vec<u64> = upload.chunks.into_values().collect();
+
+        if let Some((file_id, content_encoding)) = target {
+            if !state.set_encoding(&file_id, content_encoding, &upload_id, chunk_sizes, file_hash) {
+                chunk_store::remove_all(&upload_id);
+            }
+            return Ok(file_id);
+        }
+
+        if !state.add_blob_ref(&file_hash, &upload_id, upload.total_size) {
+            // Identical content is already stored, drop the staged copy.
+            chunk_store::remove_all(&upload_id);
+        }
+        let mime_type = mime::resolve(upload.content_type.as_deref(), &upload.filename, &first_chunk, &state.config.mime_types);
+        let file_id = state.publish(upload_id, caller, upload.filename, mime_type, chunk_sizes, file_hash);
+        if let Some(acl) = upload.acl {
+            state.files.get_mut(&file_id).unwrap().acl = acl;
+        }
+        if keep_content {
+            state.auto_compress(&file_id, &content, now);
+        }
+
+        Ok(file_id)
+    })
+}
+
+#[ic_cdk::update(name = "upload_encoding")]
+fn upload_encoding(file_id: String, content_encoding: String, content: Vec<u8>) -> ResultFileInfo {
+    let caller = authorize("upload_encoding")?;
+    encoding::validate(&content_encoding).map_err(DomainError::InvalidInput)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.config.uploads_enabled {
+            return Err(DomainError::ServiceUnavailable("Uploads are currently disabled".to_string()));
+        }
+
+        state.check_file_size(content.len() as u64)?;
+
+        let metadata = state.authorized_file(caller, &file_id, Permission::Write, api::time())?;
+        if !metadata.is_active {
+            return Err(DomainError::InvalidState("File is not active".to_string()));
+        }
+        encoding::check_decodes_to(&content_encoding, &content, metadata.size, metadata.file_hash.as_deref().unwrap_or_default())
+            .map_err(DomainError::InvalidData)?;
+        state.check_quota(metadata.owner, content.len() as u64, 0)?;
+
+        let storage_key = state.generate_id(get_current_time());
+        let file_hash = hash_data(&content);
+        let size = content.len() as u64;
+        if state.set_encoding(&file_id, content_encoding, &storage_key, vec![size], file_hash) {
+            chunk_store::put(&storage_key, 0, content);
+        }
+
+        Ok(FileInfo::from(&state.files[&file_id]))
+    })
+}
+
+#[ic_cdk::update(name = "begin_encoding_upload")]
+fn begin_encoding_upload(file_id: String, content_encoding: String, chunk_count: u32, total_size: u64) -> ResultUploadId {
+    let caller = authorize("begin_encoding_upload")?;
+    encoding::validate(&content_encoding).map_err(DomainError::InvalidInput)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.config.uploads_enabled {
+            return Err(DomainError::ServiceUnavailable("Uploads are currently disabled".to_string()));
+        }
+
+        if chunk_count == 0 {
+            return Err(DomainError::InvalidInput("Chunk count must be at least 1".to_string()));
+        }
+
+        state.check_file_size(total_size)?;
+
+        let metadata = state.authorized_file(caller, &file_id, Permission::Write, api::time())?;
+        if !metadata.is_active {
+            return Err(DomainError::InvalidState("File is not active".to_string()));
+        }
+        let (filename, target_revision) = (metadata.filename.clone(), metadata.current_revision);
+        state.check_quota(metadata.owner, total_size, 0)?;
+
+        let now = get_current_time();
+        state.purge_expired_uploads(now);
+
+        let upload_id = state.generate_id(now);
+        state.uploads.insert(upload_id.clone(), UploadSession {
+            owner: caller,
+            filename,
+            chunk_count,
+            total_size,
+            received_bytes: 0,
+            chunks: BTreeMap::new(),
+            last_activity: now,
+            content_type: None,
+            target: Some((file_id, content_encoding)),
+            target_revision,
+            acl: None,
+        });
+
+        Ok(upload_id)
+    })
+}
+
+#[ic_cdk::update(name = "abort_upload")]
+fn abort_upload(upload_id: String) -> Result<(), DomainError> {
+    let caller = authorize("abort_upload")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match state.uploads.get(&upload_id) {
+            Some(upload) if upload.owner == caller => {
+                state.uploads.remove(&upload_id);
+                chunk_store::remove_all(&upload_id);
+                Ok(())
+            }
+            Some(_) => Err(DomainError::Forbidden("Access denied".to_string())),
+            None => Err(DomainError::NotFound("Upload session not found or expired".to_string())),
+        }
+    })
+}
+
+#[ic_cdk::query(name = "get_file")]
+fn get_file(file_id: String, revision: Option<u32>, token: Option<String>) -> ResultFile {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let metadata = match &token {
+            Some(token) => {
+                let (link, metadata) = state.signed_file(token, &file_id, api::time())?;
+                if link.is_use_limited() {
+                    return Err(DomainError::InvalidInput("Links with a use limit must be redeemed with get_file_signed".to_string()));
+                }
+                metadata
+            }
+            None => state.readable_file(caller, &file_id, api::time())?,
+        };
+        file_contents(&state, metadata, revision)
+    })
+}
+
+#[ic_cdk::update(name = "get_file_signed")]
+fn get_file_signed(file_id: String, token: String, revision: Option<u32>) -> ResultFile {
+    authorize("get_file_signed")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contents = {
+            let (_, metadata) = state.signed_file(&token, &file_id, api::time())?;
+            file_contents(&state, metadata, revision)?
+        };
+        state.redeem_signed_link(&token)?;
+        Ok(contents)
+    })
+}
+
+fn file_contents(state: &State, metadata: &FileMetadata, revision: Option<u32>) -> ResultFile {
+    let revision = metadata.revision(revision.unwrap_or(metadata.current_revision))
+        .ok_or_else(|| DomainError::NotFound("Revision not found".to_string()))?;
+
+    if revision.size > MAX_RESPONSE_SIZE {
+        return Err(DomainError::LimitExceeded("File too large for a single response, use get_file_chunk or get_file_range".to_string()));
+    }
+
+    let mut content = Vec::with_capacity(revision.size as usize);
+    for index in 0..revision.chunk_count {
+        let data = state.read_blob_chunk(&revision.file_hash, index)
+            .ok_or_else(|| DomainError::DataCorruption("File content not found".to_string()))?;
+        content.extend_from_slice(&data);
+    }
+
+    Ok(FileContents {
+        filename: metadata.filename.clone(),
+        content,
+    })
+}
+
+#[ic_cdk::query(name = "list_revisions")]
+fn list_revisions(path: String) -> ResultRevisionVec {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let file_id = state.paths.get(&paths::normalize(&path))
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        let metadata = state.readable_file(caller, file_id, api::time())?;
+
+        Ok(metadata.revisions.iter()
+            .map(|revision| RevisionInfo {
+                revision: revision.revision,
+                size: revision.size,
+                file_hash: revision.file_hash.clone(),
+                mime_type: revision.mime_type.clone(),
+                uploaded_by: revision.uploaded_by.to_string(),
+                uploaded_at: revision.uploaded_at,
+                is_current: revision.revision == metadata.current_revision,
+            })
+            .collect())
+    })
+}
+
+#[ic_cdk::update(name = "rollback_file")]
+fn rollback_file(path: String, revision: u32) -> ResultFileInfo {
+    let caller = authorize("rollback_file")?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let path = paths::normalize(&path);
+        let file_id = state.paths.get(&path).cloned()
+            .ok_or_else(|| DomainError::NotFound("File not found".to_string()))?;
+        let target = state.authorized_file(caller, &file_id, Permission::Write, api::time())?
+            .revision(revision)
+            .cloned()
+            .ok_or_else(|| DomainError::NotFound("Revision not found".to_string()))?;
+
+        let info = state.update_file(&file_id, |metadata| {
+            metadata.apply_revision(&target);
+            FileInfo::from(&*metadata)
+        });
+        certification::certify_file(&state, &file_id, &path);
+
+        info.ok_or_else(|| DomainError::NotFound("File not found".to_string()))
+    })
+}
+
+#[ic_cdk::query(name = "get_file_info")]
+fn get_file_info(file_id: String) -> ResultFileInfo {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.readable_file(caller, &file_id, api::time()).map(FileInfo::from)
+    })
+}
+
+#[ic_cdk::query(name = "get_file_chunk")]
+fn get_file_chunk(file_id: String, index: u32) -> ResultChunk {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let metadata = state.readable_file(caller, &file_id, api::time())?;
+
+        if index >= metadata.chunk_count {
+            return Err(DomainError::InvalidInput(format!("Chunk index {} out of range (chunk count {})", index, metadata.chunk_count)));
+        }
+
+        state.read_chunk(metadata, index)
+            .map(|data| FileChunk { file_id: file_id.clone(), index, data })
+            .ok_or_else(|| DomainError::DataCorruption("File content not found".to_string()))
+    })
+}
+
+#[ic_cdk::query(name = "get_file_range")]
+fn get_file_range(file_id: String, offset: u64, length: u64) -> ResultBytes {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let metadata = state.readable_file(caller, &file_id, api::time())?;
+        state.read_range(metadata, offset, length)
+    })
+}
+
+#[ic_cdk::query(name = "list_files")]
+fn list_files() -> ResultFileInfoVec {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let files: Vec<FileInfo> = state.index.uploaded(..)
+            .filter_map(|(_, file_id)| state.files.get(file_id))
+            .filter(|metadata| state.permits(caller, metadata, Permission::Read, api::time()))
+            .map(FileInfo::from)
+            .collect();
+
+        Ok(files)
+    })
+}
+
+#[ic_cdk::query(name = "list_files_paged")]
+fn list_files_paged(
+    cursor: Option<String>,
+    limit: Option<u32>,
+    sort_by: Option<SortBy>,
+    order: Option<SortOrder>,
+    filter: Option<FileFilter>,
+) -> ResultFilePage {
+    let caller = get_caller_id();
+    let sort_by = sort_by.unwrap_or_default();
+    let order = order.unwrap_or_default();
+    let filter = filter.unwrap_or_default();
+    let limit = limit.unwrap_or(listing::DEFAULT_PAGE_SIZE).clamp(1, listing::MAX_PAGE_SIZE) as usize;
+    let cursor = cursor.map(|cursor| Cursor::decode(&cursor, sort_by, order)).transpose().map_err(DomainError::InvalidInput)?;
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let (files, next_cursor) = listing::page(&state, &filter, sort_by, order, cursor.as_ref(), limit, |metadata| {
+            metadata.is_active && state.permits(caller, metadata, Permission::Read, api::time())
+        });
+        Ok(FilePage {
+            files: files.into_iter().map(FileInfo::from).collect(),
+            next_cursor,
+        })
+    })
+}
+
+#[ic_cdk::query(name = "list_directory")]
+fn list_directory(prefix: String, delimiter: String) -> ResultDirectoryListing {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let prefix = paths::normalize(&prefix);
+        let listing = paths::list(&state.paths, &prefix, &delimiter, |file_id| {
+            state.files.get(file_id)
+                .map(|metadata| metadata.is_active && state.permits(caller, metadata, Permission::Read, api::time()))
+                .unwrap_or(false)
+        });
+
+        Ok(DirectoryListing {
+            folders: listing.folders.into_iter().collect(),
+            files: listing.file_ids.into_iter()
+                .map(|file_id| FileInfo::from(&state.files[file_id]))
+                .collect(),
+            prefix,
+        })
+    })
 }
 
-#[ic_cdk::query(name = "verify_session")]
-fn verify_session(session_id: String) -> Result<Session, String> {
-    validate_session(&session_id)
-        .ok_or_else(|| "Invalid or expired session".to_string())
+#[ic_cdk::update(name = "move_file")]
+fn move_file(path: String, new_path: String) -> ResultFileInfo {
+    let caller = authorize("move_file")?;
+    let new_path = paths::validate(&new_path).map_err(DomainError::InvalidInput)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.move_file(caller, &paths::normalize(&path), new_path, api::time())
+            .map(FileInfo::from)
+    })
 }
 
-#[ic_cdk::update(name = "login")]
-fn login() -> Result<(String, Session), String> {
-    let caller = get_caller_id();
-    
+#[ic_cdk::update(name = "rename_file")]
+fn rename_file(path: String, new_name: String) -> ResultFileInfo {
+    let caller = authorize("rename_file")?;
+    if new_name.contains('/') {
+        return Err(DomainError::InvalidInput("New name must not contain '/', use move_file to change directories".to_string()));
+    }
+    let path = paths::normalize(&path);
+    let parent = &path[..path.rfind('/').unwrap_or(0)];
+    let new_path = paths::validate(&format!("{}/{}", parent, new_name)).map_err(DomainError::InvalidInput)?;
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Ensure user exists
-        if !state.users.contains_key(&caller) {
-            return Err("User not registered".to_string());
-        }
-        
-        // Create new session
-        let session = create_session(
-            caller,
-            state.roles.get(&caller).cloned().unwrap_or_default(),
-        );
-        
-    // Generate session ID
-    let session_id = generate_id();
-        
-        // Store session
-        state.sessions.insert(session_id.clone(), session.clone());
-        
-        Ok((session_id, session))
+        state.move_file(caller, &path, new_path, api::time())
+            .map(FileInfo::from)
     })
 }
 
-#[ic_cdk::update(name = "register")]
-fn register(username: String, email: Option<String>) -> Result<User, String> {
-    let caller = get_caller_id();
-    
+#[ic_cdk::update(name = "delete_file")]
+fn delete_file(file_id: String) -> ResultText {
+    let caller = authorize("delete_file")?;
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Check if user already exists
-        if state.users.contains_key(&caller) {
-            return Err("User already registered".to_string());
-        }
-        
-        // Create new user
-        let user = User {
-            id: caller.to_string(),
-            username,
-            role: Role::Viewer, // Default role
-            email,
-            is_active: true,
-        };
-        
-        // Store user and default role
-        state.users.insert(caller, user.clone());
-        state.roles.insert(caller, vec![Role::Viewer]);
-        
-        Ok(user)
+        let path = state.delete_file(caller, &file_id, api::time())?;
+        certification::certify_file(&state, &file_id, &path);
+        Ok("File deleted successfully".to_string())
     })
 }
 
-#[ic_cdk::update(name = "logout")]
-fn logout(session_id: String) -> Result<(), String> {
+#[ic_cdk::query(name = "get_file_acl")]
+fn get_file_acl(file_id: String) -> ResultFileAcl {
+    let caller = get_caller_id();
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.acl_manageable_file(caller, &file_id, api::time())
+            .map(|metadata| metadata.acl.clone())
+    })
+}
+
+#[ic_cdk::update(name = "set_file_acl")]
+fn set_file_acl(file_id: String, acl: FileAcl) -> ResultFileAcl {
+    let caller = authorize("set_file_acl")?;
+    acl.validate().map_err(DomainError::InvalidInput)?;
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        state.sessions.remove(&session_id);
-        Ok(())
+        state.acl_manageable_file(caller, &file_id, api::time())?;
+        state.files.get_mut(&file_id).unwrap().acl = acl.clone();
+        Ok(acl)
     })
 }
 
-// Canister API Implementation
-#[ic_cdk::init]
-fn init() {
+#[ic_cdk::update(name = "set_file_visibility")]
+fn set_file_visibility(file_id: String, visibility: Option<Visibility>) -> ResultFileInfo {
+    let caller = authorize("set_file_visibility")?;
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let admin = get_caller_id();
-        state.roles.insert(admin, vec![Role::Admin]);
-    });
+        state.acl_manageable_file(caller, &file_id, api::time())?;
+        let metadata = state.files.get_mut(&file_id).unwrap();
+        metadata.visibility = visibility;
+        Ok(FileInfo::from(&*metadata))
+    })
 }
 
-#[ic_cdk::query(name = "health")]
-fn health() -> String {
-    "healthy".to_string()
+// Directories have no owner, so only admins may change what they expose.
+#[ic_cdk::update(name = "set_directory_visibility")]
+fn set_directory_visibility(path: String, visibility: Option<Visibility>) -> Result<(), DomainError> {
+    authorize("set_directory_visibility")?;
+    let path = paths::normalize_directory(&path);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match visibility {
+            Some(visibility) => state.directory_visibility.insert(path, visibility),
+            None => state.directory_visibility.remove(&path),
+        };
+        Ok(())
+    })
 }
 
-#[ic_cdk::query(name = "stats")]
-fn stats() -> String {
+// Only admins see private directories; the names alone can give too much
+// away.
+#[ic_cdk::query(name = "list_directory_visibility")]
+fn list_directory_visibility() -> Vec<(String, Visibility)> {
+    let caller = get_caller_id();
     STATE.with(|state| {
         let state = state.borrow();
-        format!(
-            "Files: {}, Users: {}, Active uploads enabled: {}",
-            state.files.len(),
-            state.users.len(),
-            state.config.uploads_enabled
-        )
+        let show_private = state.check_active(caller).is_ok() && state.is_admin(caller, api::time());
+        state.directory_visibility.iter()
+            .filter(|(_, visibility)| show_private || **visibility == Visibility::Public)
+            .map(|(path, visibility)| (path.clone(), *visibility))
+            .collect()
     })
 }
 
-#[ic_cdk::update(name = "upload_file")]
-fn upload_file(filename: String, content: Vec<u8>) -> ResultText {
-    let caller = get_caller_id();
+#[ic_cdk::update(name = "create_signed_url")]
+async fn create_signed_url(file_id: String, ttl_secs: u64, max_uses: Option<u32>) -> ResultSignedUrl {
+    let caller = authorize("create_signed_url")?;
+    signed_url::validate(ttl_secs, max_uses).map_err(DomainError::InvalidInput)?;
+    STATE.with(|state| state.borrow().acl_manageable_file(caller, &file_id, api::time()).map(|_| ()))?;
+
+    let (random,) = raw_rand().await
+        .map_err(|(code, message)| DomainError::ServiceUnavailable(format!("Failed to generate link token: {:?} {}", code, message)))?;
+    let token = hex::encode(random);
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if !state.config.uploads_enabled {
-            return Err("Uploads are currently disabled".to_string());
-        }
-        
-        if content.len() as u64 > state.config.max_file_size_bytes {
-            return Err("File size exceeds maximum allowed".to_string());
+        // The file may have changed hands or been deleted during the call.
+        if !state.acl_manageable_file(caller, &file_id, api::time())?.is_active {
+            return Err(DomainError::InvalidState("File is not active".to_string()));
         }
 
-    let file_id = generate_id();
-        let file_hash = hash_data(&content);
-        
-        let metadata = FileMetadata {
-            id: file_id.clone(),
-            owner: caller,
-            owner_id: caller.to_string(),
-            filename: filename.clone(),
-            size: content.len() as u64,
-            mime_type: "application/octet-stream".to_string(),
-            uploaded_at: api::time(),
-            roles_allowed: vec![Role::Admin, Role::Publisher, Role::Viewer],
-            chunk_count: 1,
-            is_active: true,
-            file_hash: Some(file_hash),
-        };
-
-        let chunk = FileChunk {
+        let now = api::time();
+        let expires_at = now.saturating_add(ttl_secs.saturating_mul(1_000_000_000));
+        let id = signed_url::link_id(&token);
+        state.signed_links.insert(id.clone(), SignedLink {
             file_id: file_id.clone(),
-            index: 0,
-            data: content,
-        };
-
-        state.files.insert(file_id.clone(), metadata);
-        state.chunks.insert(file_id.clone(), vec![chunk]);
+            created_by: caller,
+            created_at: now,
+            expires_at,
+            max_uses,
+            uses: 0,
+        });
 
-        Ok(file_id)
+        let origin = match &state.config.cdn_domain {
+            Some(domain) if domain.contains("://") => domain.trim_end_matches('/').to_string(),
+            Some(domain) => format!("https://{}", domain.trim_end_matches('/')),
+            None => String::new(),
+        };
+        Ok(SignedUrl {
+            url: format!("{}{}?token={}", origin, certification::file_path(&file_id), token),
+            id,
+            token,
+            expires_at,
+        })
     })
 }
 
-#[ic_cdk::query(name = "get_file")]
-fn get_file(file_id: String) -> ResultFile {
+#[ic_cdk::query(name = "list_signed_urls")]
+fn list_signed_urls(file_id: String) -> ResultSignedUrlInfoVec {
     let caller = get_caller_id();
     STATE.with(|state| {
         let state = state.borrow();
-        
-        if let Some(metadata) = state.files.get(&file_id) {
-            if !metadata.is_active {
-                return Err("File is not active".to_string());
-            }
-
-            let has_access = state.roles.get(&caller)
-                .map(|roles| roles.iter().any(|role| metadata.roles_allowed.contains(role)))
-                .unwrap_or(false);
-
-            if !has_access && metadata.owner != caller {
-                return Err("Access denied".to_string());
-            }
-
-            if let Some(chunks) = state.chunks.get(&file_id) {
-                let content = chunks.iter()
-                    .flat_map(|chunk| chunk.data.clone())
-                    .collect();
+        state.acl_manageable_file(caller, &file_id, api::time())?;
+        Ok(state.signed_links.iter()
+            .filter(|(_, link)| link.file_id == file_id)
+            .map(|(id, link)| SignedUrlInfo::new(id, link))
+            .collect())
+    })
+}
 
-                Ok(FileContents {
-                    filename: metadata.filename.clone(),
-                    content,
-                })
-            } else {
-                Err("File content not found".to_string())
-            }
-        } else {
-            Err("File not found".to_string())
+// The creator of a link, the owner of the file and admins may revoke it.
+#[ic_cdk::update(name = "revoke_signed_url")]
+fn revoke_signed_url(id: String) -> Result<(), DomainError> {
+    let caller = authorize("revoke_signed_url")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let link = state.signed_links.get(&id)
+            .ok_or_else(|| DomainError::NotFound("Link not found".to_string()))?;
+        if link.created_by != caller {
+            state.acl_manageable_file(caller, &link.file_id, api::time())?;
         }
+        state.signed_links.remove(&id);
+        Ok(())
     })
 }
 
-#[ic_cdk::query(name = "list_files")]
-fn list_files() -> ResultFileInfoVec {
+#[ic_cdk::query(name = "http_request")]
+fn http_request(req: HttpRequest) -> HttpResponse {
     let caller = get_caller_id();
-    STATE.with(|state| {
-        let state = state.borrow();
-        let user_roles = state.roles.get(&caller);
-        
-        let files: Vec<FileInfo> = state.files.values()
-            .filter(|metadata| {
-                metadata.is_active && (
-                    metadata.owner == caller ||
-                    user_roles.map(|roles| roles.iter().any(|role| metadata.roles_allowed.contains(role))).unwrap_or(false)
-                )
-            })
-            .map(|metadata| FileInfo {
-                id: metadata.id.clone(),
-                filename: metadata.filename.clone(),
-                uploader: metadata.owner_id.clone(),
-                uploaded_at: metadata.uploaded_at,
-            })
-            .collect();
-
-        Ok(files)
-    })
+    STATE.with(|state| http::handle_request(&state.borrow(), caller, &req, false, api::time()).0)
 }
 
-#[ic_cdk::update(name = "delete_file")]
-fn delete_file(file_id: String) -> ResultText {
+// Redeems uses of use-limited links. The rest of such a response streams
+// through a grant rather than the link, which may have no uses left by then.
+#[ic_cdk::update(name = "http_request_update")]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
     let caller = get_caller_id();
+    if let Err(e) = check_rate_limit(caller, "http_request_update") {
+        return HttpResponse::text(429, &e.to_string());
+    }
+    let now = api::time();
     STATE.with(|state| {
-        // To avoid simultaneous immutable + mutable borrows of state,
-        // do a short immutable borrow first to check access, then perform
-        // the mutable operations in a separate scope.
-        {
-            let state_ref = state.borrow();
-            if !state_ref.files.contains_key(&file_id) {
-                return Err("File not found".to_string());
-            }
-            let metadata = state_ref.files.get(&file_id).unwrap();
-            if metadata.owner != caller && !state_ref.roles.get(&caller).map(|roles| roles.contains(&Role::Admin)).unwrap_or(false) {
-                return Err("Access denied".to_string());
+        let mut state = state.borrow_mut();
+        let (mut response, redeemed) = http::handle_request(&state, caller, &req, true, now);
+        if let Some(token) = redeemed {
+            if let Err(e) = state.redeem_signed_link(&token) {
+                trap(&e.to_string());
             }
+            http::finish_redeemed(&mut state, &mut response, &token, now);
         }
-
-        // Now take a mutable borrow and perform the update
-        let mut state_mut = state.borrow_mut();
-        if let Some(metadata) = state_mut.files.get_mut(&file_id) {
-            metadata.is_active = false;
-            state_mut.chunks.remove(&file_id);
-            Ok("File deleted successfully".to_string())
-        } else {
-            Err("File not found".to_string())
-        }
+        response
     })
 }
 
+#[ic_cdk::query(name = "http_request_streaming_callback")]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let caller = get_caller_id();
+    STATE.with(|state| http::stream_chunk(&state.borrow(), caller, token, api::time()))
+        .unwrap_or_else(|e| trap(&e))
+}
+
 #[ic_cdk::update(name = "wipe_all")]
 fn wipe_all() -> ResultText {
-    check_admin()?;
+    authorize("wipe_all")?;
     
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         state.files.clear();
-        state.chunks.clear();
+        state.blobs.clear();
+        state.paths.clear();
+        state.index.clear();
+        state.signed_links.clear();
+        state.stream_grants.clear();
+        state.uploads.clear();
+        chunk_store::clear();
+        certification::rebuild(&state);
         Ok("All files wiped successfully".to_string())
     })
 }
@@ -511,43 +2279,116 @@ fn whoami() -> (String, Vec<Role>) {
         let state = state.borrow();
         (
             caller.to_string(),
-            state.roles.get(&caller).cloned().unwrap_or_default()
+            state.roles_of(caller).to_vec()
         )
     })
 }
 
 #[ic_cdk::update(name = "grant_role")]
 fn grant_role(principal_text: String, role: Role) -> ResultRoleVec {
-    check_admin()?;
+    authorize("grant_role")?;
     
     let principal = Principal::from_text(principal_text)
-        .map_err(|e| format!("Invalid principal: {}", e))?;
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let roles = state.roles.entry(principal).or_insert_with(Vec::new);
-        if !roles.contains(&role) {
-            roles.push(role);
+        let user = state.users.entry(principal)
+            .or_insert_with(|| User::placeholder(principal));
+        if !user.roles.contains(&role) {
+            user.roles.push(role);
         }
-        Ok(roles.clone())
+        Ok(user.roles.clone())
     })
 }
 
 #[ic_cdk::update(name = "revoke_role")]
 fn revoke_role(principal_text: String, role: Role) -> ResultRoleVec {
-    check_admin()?;
+    authorize("revoke_role")?;
     
     let principal = Principal::from_text(principal_text)
-        .map_err(|e| format!("Invalid principal: {}", e))?;
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if let Some(roles) = state.roles.get_mut(&principal) {
-            roles.retain(|r| r != &role);
-            Ok(roles.clone())
-        } else {
-            Ok(vec![])
+        if role == Role::Admin {
+            state.check_not_last_admin(principal)?;
+        }
+        match state.users.get_mut(&principal) {
+            Some(user) => {
+                user.roles.retain(|r| r != &role);
+                Ok(user.roles.clone())
+            }
+            None => Ok(vec![]),
+        }
+    })
+}
+
+// First step of handing the Admin role from the caller to `to`; nothing
+// changes until `to` accepts. A new proposal to the same principal replaces
+// the previous one.
+#[ic_cdk::update(name = "propose_admin_transfer")]
+fn propose_admin_transfer(to: String) -> Result<AdminTransfer, DomainError> {
+    let caller = authorize("propose_admin_transfer")?;
+    let to = Principal::from_text(to)
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
+    if to == caller || to == Principal::anonymous() {
+        return Err(DomainError::InvalidInput(format!("Cannot transfer the Admin role to {}", to)));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.check_active(to)?;
+        let now = api::time();
+        state.admin_transfers.retain(|_, transfer| transfer.expires_at > now);
+        let transfer = AdminTransfer {
+            from: caller,
+            to,
+            proposed_at: now,
+            expires_at: now + ADMIN_TRANSFER_TTL * 1_000_000_000,
+        };
+        state.admin_transfers.insert(to, transfer.clone());
+        Ok(transfer)
+    })
+}
+
+// Second step, called by the recipient: they become an admin and the
+// proposer stops being one. The new role shows up in sessions started after
+// accepting.
+#[ic_cdk::update(name = "accept_admin_transfer")]
+fn accept_admin_transfer() -> Result<User, DomainError> {
+    let caller = authorize("accept_admin_transfer")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let transfer = state.admin_transfers.remove(&caller)
+            .filter(|transfer| transfer.expires_at > api::time())
+            .ok_or_else(|| DomainError::NotFound("No admin transfer is waiting for the caller".to_string()))?;
+        if !state.roles_of(transfer.from).contains(&Role::Admin) {
+            return Err(DomainError::InvalidState(format!("{} is no longer an admin", transfer.from)));
+        }
+
+        let user = state.users.entry(caller)
+            .or_insert_with(|| User::new(caller, caller.to_string(), Vec::new()));
+        if !user.roles.contains(&Role::Admin) {
+            user.roles.push(Role::Admin);
+        }
+        let user = user.clone();
+        if let Some(from) = state.users.get_mut(&transfer.from) {
+            from.roles.retain(|role| *role != Role::Admin);
         }
+        Ok(user)
+    })
+}
+
+#[ic_cdk::update(name = "cancel_admin_transfer")]
+fn cancel_admin_transfer(to: String) -> Result<(), DomainError> {
+    authorize("cancel_admin_transfer")?;
+    let to = Principal::from_text(to)
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
+    STATE.with(|state| {
+        state.borrow_mut().admin_transfers.remove(&to)
+            .map(|_| ())
+            .ok_or_else(|| DomainError::NotFound("No admin transfer to cancel".to_string()))
     })
 }
 
@@ -556,11 +2397,11 @@ fn list_roles_of(principal_text: String) -> ResultRoleVec {
     check_admin()?;
     
     let principal = Principal::from_text(principal_text)
-        .map_err(|e| format!("Invalid principal: {}", e))?;
+        .map_err(|e| DomainError::InvalidInput(format!("Invalid principal: {}", e)))?;
 
     STATE.with(|state| {
         let state = state.borrow();
-        Ok(state.roles.get(&principal).cloned().unwrap_or_default())
+        Ok(state.roles_of(principal).to_vec())
     })
 }
 
@@ -570,14 +2411,97 @@ fn list_all_user_roles() -> ResultRoleMap {
     
     STATE.with(|state| {
         let state = state.borrow();
-        let roles_map: Vec<(String, Vec<Role>)> = state.roles
+        let roles_map: Vec<(String, Vec<Role>)> = state.users
             .iter()
-            .map(|(principal, roles)| (principal.to_string(), roles.clone()))
+            .map(|(principal, user)| (principal.to_string(), user.roles.clone()))
             .collect();
         Ok(roles_map)
     })
 }
 
+#[ic_cdk::query(name = "get_usage")]
+fn get_usage(user_principal: String) -> Result<Usage, DomainError> {
+    let caller = get_caller_id();
+    let principal = Principal::from_text(&user_principal)
+        .map_err(|_| DomainError::InvalidInput("Invalid principal".to_string()))?;
+    STATE.with(|state| {
+        let state = state.borrow();
+        let roles = state.signed_in_roles(caller, api::time())?;
+        if principal != caller && !roles.contains(&Role::Admin) {
+            return Err(DomainError::Forbidden("Only admins can view the usage of other users".to_string()));
+        }
+        let quota = state.quota_of(principal);
+        let (used_bytes, used_files) = state.usage_of(principal);
+        Ok(Usage {
+            principal: principal.to_string(),
+            used_bytes,
+            used_files,
+            max_bytes: quota.max_bytes,
+            max_files: quota.max_files,
+        })
+    })
+}
+
+#[ic_cdk::update(name = "set_user_quota")]
+fn set_user_quota(user_principal: String, quota: Option<Quota>) -> Result<(), DomainError> {
+    authorize("set_user_quota")?;
+    let principal = Principal::from_text(&user_principal)
+        .map_err(|_| DomainError::InvalidInput("Invalid principal".to_string()))?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match quota {
+            Some(quota) => state.quota_overrides.insert(principal, quota),
+            None => state.quota_overrides.remove(&principal),
+        };
+        Ok(())
+    })
+}
+
+// Drops ingress calls from deactivated users, calls that the permission
+// matrix refuses and calls whose caller is out of calls for the method,
+// before they cost any cycles. Nothing is used
+// up here: state changes made while inspecting are discarded, the method
+// itself takes the call. Queries called as updates are not in the matrix and
+// pass through.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = api::call::method_name();
+    let caller = api::caller();
+    let accepted = STATE.with(|state| {
+        let state = state.borrow();
+        let permitted = state.check_active(caller).is_ok()
+            && (permissions::access(&method).is_none()
+                || permissions::check(&method, caller, state.session_roles(caller, api::time()).as_deref()).is_ok());
+        permitted && state.config.rate_limit(&method)
+            .is_none_or(|limit| rate_limit::allows(caller, &method, &limit, api::time()))
+    });
+    if accepted {
+        api::call::accept_message();
+    }
+}
+
+#[ic_cdk::update(name = "set_rate_limit")]
+fn set_rate_limit(method: String, limit: Option<RateLimit>) -> ResultConfig {
+    authorize("set_rate_limit")?;
+    // Limits are enforced where update methods are authorized; queries and
+    // unknown names would never be counted.
+    if permissions::access(&method).is_none() {
+        return Err(DomainError::InvalidInput(format!("{} is not an update method", method)));
+    }
+    if let Some(limit) = &limit {
+        rate_limit::validate(limit).map_err(DomainError::InvalidInput)?;
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config.rate_limits.retain(|(limited, _)| *limited != method);
+        if let Some(limit) = limit {
+            state.config.rate_limits.push((method, limit));
+        }
+        state.config.last_updated_nanos = api::time();
+        Ok(state.config.clone())
+    })
+}
+
 #[ic_cdk::query(name = "get_config")]
 fn get_config() -> Config {
     STATE.with(|state| state.borrow().config.clone())
@@ -588,8 +2512,9 @@ fn update_config(
     max_file_size_bytes: Option<u64>,
     uploads_enabled: Option<bool>,
     cdn_domain: Option<Option<String>>,
+    auto_compress: Option<bool>,
 ) -> ResultConfig {
-    check_admin()?;
+    authorize("update_config")?;
     
     STATE.with(|state| {
         let mut state = state.borrow_mut();
@@ -602,6 +2527,51 @@ fn update_config(
         if let Some(domain) = cdn_domain {
             state.config.cdn_domain = domain;
         }
+        if let Some(enabled) = auto_compress {
+            state.config.auto_compress = enabled;
+        }
+        state.config.last_updated_nanos = api::time();
+        Ok(state.config.clone())
+    })
+}
+
+#[ic_cdk::update(name = "set_mime_type")]
+fn set_mime_type(extension: String, content_type: String) -> ResultConfig {
+    authorize("set_mime_type")?;
+    mime::validate(&content_type).map_err(DomainError::InvalidInput)?;
+
+    let extension = mime::normalize_extension(&extension);
+    if extension.is_empty() {
+        return Err(DomainError::InvalidInput("Extension must not be empty".to_string()));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config.mime_types.insert(extension, content_type.trim().to_string());
+        state.config.last_updated_nanos = api::time();
+        Ok(state.config.clone())
+    })
+}
+
+#[ic_cdk::update(name = "set_role_quota")]
+fn set_role_quota(role: Role, quota: Quota) -> ResultConfig {
+    authorize("set_role_quota")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config.role_quotas.retain(|(quota_role, _)| *quota_role != role);
+        state.config.role_quotas.push((role, quota));
+        state.config.last_updated_nanos = api::time();
+        Ok(state.config.clone())
+    })
+}
+
+#[ic_cdk::update(name = "remove_mime_type")]
+fn remove_mime_type(extension: String) -> ResultConfig {
+    authorize("remove_mime_type")?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config.mime_types.remove(&mime::normalize_extension(&extension));
         state.config.last_updated_nanos = api::time();
         Ok(state.config.clone())
     })
@@ -609,11 +2579,12 @@ fn update_config(
 
 #[ic_cdk::update(name = "reset_config")]
 fn reset_config() -> ResultConfig {
-    check_admin()?;
+    authorize("reset_config")?;
     
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         state.config = Config::default();
+        state.config.last_updated_nanos = api::time();
         Ok(state.config.clone())
     })
 }
@@ -622,3 +2593,253 @@ fn reset_config() -> ResultConfig {
 fn __get_candid_interface_tmp_hack() -> String {
     include_str!("../cdn_app_backend.did").to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Grantee;
+
+    const NOW: u64 = 1_000_000_000;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    // Publishes `content`, split into chunks of `chunk_size` bytes, as
+    // revision 1 of a new file.
+    fn store_file(state: &mut State, id: &str, path: &str, owner: Principal, content: &[u8], chunk_size: usize) {
+        let file_hash = hash_data(content);
+        if state.add_blob_ref(&file_hash, id, content.len() as u64) {
+            for (index, chunk) in content.chunks(chunk_size).enumerate() {
+                chunk_store::put(id, index as u32, chunk.to_vec());
+            }
+        }
+        let chunk_sizes: Vec<u64> = content.chunks(chunk_size).map(|chunk| chunk.len() as u64).collect();
+        let revision = FileRevision {
+            revision: 1,
+            size: content.len() as u64,
+            chunk_count: chunk_sizes.len() as u32,
+            chunk_sizes,
+            file_hash,
+            mime_type: "text/plain".to_string(),
+            encodings: BTreeMap::new(),
+            uploaded_by: owner,
+            uploaded_at: 0,
+        };
+        let mut metadata = FileMetadata::for_tests(id, path, owner, 0);
+        metadata.apply_revision(&revision);
+        metadata.revisions.push(revision);
+        state.index.insert(&metadata);
+        state.paths.insert(path.to_string(), id.to_string());
+        state.files.insert(id.to_string(), metadata);
+    }
+
+    #[test]
+    fn seeding_restores_an_admin_and_keeps_the_record() {
+        let mut state = State::default();
+        let mut former = User::new(principal(1), "former".to_string(), vec![Role::Admin, Role::Publisher]);
+        former.is_active = false;
+        state.users.insert(principal(1), former);
+        assert!(!state.has_active_admin());
+
+        state.seed_admins(vec![principal(1), principal(2)]);
+        assert!(state.has_active_admin());
+        let restored = &state.users[&principal(1)];
+        assert!(restored.is_active);
+        assert_eq!(restored.username, "former");
+        assert_eq!(restored.roles, vec![Role::Admin, Role::Publisher]);
+        assert_eq!(state.users[&principal(2)].roles, vec![Role::Admin]);
+    }
+
+    #[test]
+    fn owners_need_an_active_account_and_a_session_to_manage_access() {
+        let owner = principal(1);
+        let mut state = State::default();
+        state.files.insert("f1".to_string(), FileMetadata::for_tests("f1", "/a.txt", owner, 0));
+        state.users.insert(owner, User::new(owner, "owner".to_string(), vec![Role::Publisher]));
+        assert!(matches!(state.acl_manageable_file(owner, "f1", NOW), Err(DomainError::Unauthorized(_))));
+
+        state.users.get_mut(&owner).unwrap().is_active = false;
+        assert!(matches!(state.acl_manageable_file(owner, "f1", NOW), Err(DomainError::Forbidden(_))));
+    }
+
+    #[test]
+    fn acl_grantees_change_files_without_being_publishers() {
+        let (owner, grantee, viewer) = (principal(1), principal(2), principal(3));
+        let mut state = State::default();
+        let mut metadata = FileMetadata::for_tests("f1", "/a.txt", owner, 0);
+        metadata.acl.write.push(Grantee::Principal(grantee));
+        metadata.acl.delete.push(Grantee::Principal(grantee));
+        state.files.insert("f1".to_string(), metadata);
+        state.paths.insert("/a.txt".to_string(), "f1".to_string());
+        for user in [grantee, viewer] {
+            state.users.insert(user, User::new(user, user.to_string(), vec![Role::Viewer]));
+            state.start_session(user, vec![Role::Viewer], NOW);
+        }
+
+        for method in ["move_file", "rename_file", "delete_file"] {
+            let roles = state.session_roles(grantee, NOW);
+            assert!(permissions::check(method, grantee, roles.as_deref()).is_ok());
+        }
+        assert!(state.authorized_file(grantee, "f1", Permission::Write, NOW).is_ok());
+        assert!(matches!(state.delete_file(viewer, "f1", NOW), Err(DomainError::Forbidden(_))));
+        assert_eq!(state.delete_file(grantee, "f1", NOW).unwrap(), "/a.txt");
+        assert!(!state.files["f1"].is_active);
+        assert!(!state.paths.contains_key("/a.txt"));
+    }
+
+    #[test]
+    fn auto_compression_stays_within_the_quota() {
+        let owner = principal(1);
+        let content = "compressible text ".repeat(100).into_bytes();
+        let mut state = State::default();
+        store_file(&mut state, "f1", "/a.txt", owner, &content, content.len());
+
+        let size = content.len() as u64;
+        state.quota_overrides.insert(owner, Quota { max_bytes: Some(size), max_files: None });
+        state.auto_compress("f1", &content, NOW);
+        assert!(state.files["f1"].encodings.is_empty());
+
+        state.quota_overrides.insert(owner, Quota { max_bytes: Some(2 * size), max_files: None });
+        state.auto_compress("f1", &content, NOW);
+        assert!(state.files["f1"].encodings.contains_key(encoding::GZIP));
+        assert!(state.usage_of(owner).0 <= 2 * size);
+    }
+
+    #[test]
+    fn only_owners_replace_the_acl_with_a_new_revision() {
+        let (owner, writer) = (principal(1), principal(2));
+        let mut state = State::default();
+        let mut metadata = FileMetadata::for_tests("f1", "/a.txt", owner, 0);
+        metadata.acl.write.push(Grantee::Principal(writer));
+        state.files.insert("f1".to_string(), metadata);
+        for user in [owner, writer] {
+            state.users.insert(user, User::new(user, user.to_string(), vec![Role::Publisher]));
+            state.start_session(user, vec![Role::Publisher], NOW);
+        }
+
+        let acl = FileAcl::default();
+        assert!(state.check_upload_acl(writer, Some("f1"), None, NOW).is_ok());
+        assert!(matches!(state.check_upload_acl(writer, Some("f1"), Some(&acl), NOW), Err(DomainError::Forbidden(_))));
+        assert!(state.check_upload_acl(owner, Some("f1"), Some(&acl), NOW).is_ok());
+        assert!(state.check_upload_acl(writer, None, Some(&acl), NOW).is_ok());
+    }
+
+    #[test]
+    fn registering_fills_in_a_placeholder_once() {
+        let (granted, newcomer) = (principal(1), principal(2));
+        let mut state = State::default();
+        state.users.insert(granted, User { roles: vec![Role::Publisher], ..User::placeholder(granted) });
+
+        let user = state.register(granted, "granted".to_string(), None).unwrap();
+        assert_eq!(user.username, "granted");
+        assert_eq!(user.roles, vec![Role::Publisher]);
+        assert!(matches!(state.register(granted, "again".to_string(), None), Err(DomainError::DuplicateEntry(_))));
+
+        let user = state.register(newcomer, "newcomer".to_string(), Some("new@example.com".to_string())).unwrap();
+        assert_eq!(user.roles, vec![Role::Viewer]);
+        assert_eq!(state.users[&newcomer].email.as_deref(), Some("new@example.com"));
+    }
+
+    #[test]
+    fn blobs_are_freed_with_their_last_reference() {
+        let owner = principal(1);
+        let mut state = State::default();
+        state.users.insert(owner, User::new(owner, "owner".to_string(), vec![Role::Publisher]));
+        state.start_session(owner, vec![Role::Publisher], NOW);
+        store_file(&mut state, "f1", "/a.txt", owner, b"shared", 6);
+        store_file(&mut state, "f2", "/b.txt", owner, b"shared", 6);
+        let shared = hash_data(b"shared");
+        assert_eq!(state.blobs[&shared].ref_count, 2);
+
+        assert!(state.set_encoding("f1", encoding::GZIP.to_string(), "gz-1", vec![2], "hash-gz".to_string()));
+        chunk_store::put("gz-1", 0, b"gz".to_vec());
+        assert!(!state.set_encoding("f2", encoding::GZIP.to_string(), "gz-2", vec![2], "hash-gz".to_string()));
+        assert_eq!(state.blobs["hash-gz"].ref_count, 2);
+
+        state.delete_file(owner, "f1", NOW).unwrap();
+        assert_eq!(state.blobs[&shared].ref_count, 1);
+        assert_eq!(state.blobs["hash-gz"].ref_count, 1);
+        assert_eq!(state.read_blob_chunk("hash-gz", 0).as_deref(), Some(&b"gz"[..]));
+
+        state.delete_file(owner, "f2", NOW).unwrap();
+        assert!(state.blobs.is_empty());
+        assert!(chunk_store::get("f1", 0).is_none());
+        assert!(chunk_store::get("gz-1", 0).is_none());
+    }
+
+    #[test]
+    fn ranges_are_sliced_across_chunk_boundaries() {
+        let mut state = State::default();
+        store_file(&mut state, "f1", "/a.txt", principal(1), b"abcdefghij", 4);
+        let metadata = &state.files["f1"];
+        let range = |offset, length| state.read_range(metadata, offset, length).unwrap();
+
+        assert_eq!(range(0, 4), b"abcd");
+        assert_eq!(range(2, 4), b"cdef");
+        assert_eq!(range(3, 6), b"defghi");
+        assert_eq!(range(4, 4), b"efgh");
+        assert_eq!(range(8, 10), b"ij");
+        assert_eq!(range(0, 100), b"abcdefghij");
+        assert!(range(4, 0).is_empty());
+        assert!(range(10, 5).is_empty());
+        assert!(matches!(state.read_range(metadata, 11, 1), Err(DomainError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn chunked_uploads_complete_once_every_byte_arrived() {
+        let (owner, other) = (principal(1), principal(2));
+        let mut state = State::default();
+        state.uploads.insert("u1".to_string(), UploadSession {
+            owner,
+            filename: "a.txt".to_string(),
+            chunk_count: 3,
+            total_size: 7,
+            received_bytes: 0,
+            chunks: BTreeMap::new(),
+            last_activity: NOW,
+            content_type: None,
+            target: None,
+            target_revision: 0,
+            acl: None,
+        });
+
+        state.put_chunk(owner, "u1", 2, b"g".to_vec(), NOW).unwrap();
+        state.put_chunk(owner, "u1", 0, b"abc".to_vec(), NOW).unwrap();
+        assert!(matches!(state.completed_upload(owner, "u1", NOW), Err(DomainError::InvalidState(_))));
+
+        state.put_chunk(owner, "u1", 1, b"de".to_vec(), NOW).unwrap();
+        assert!(matches!(state.completed_upload(owner, "u1", NOW), Err(DomainError::InvalidState(_))));
+        assert!(matches!(state.put_chunk(owner, "u1", 1, b"defgh".to_vec(), NOW), Err(DomainError::LimitExceeded(_))));
+        assert!(matches!(state.put_chunk(owner, "u1", 3, b"h".to_vec(), NOW), Err(DomainError::InvalidInput(_))));
+        assert!(matches!(state.put_chunk(other, "u1", 1, b"def".to_vec(), NOW), Err(DomainError::Forbidden(_))));
+
+        state.put_chunk(owner, "u1", 1, b"def".to_vec(), NOW).unwrap();
+        let upload = state.completed_upload(owner, "u1", NOW).unwrap();
+        assert_eq!(upload.chunks.values().copied().collect::<Vec<_>>(), vec![3, 3, 1]);
+        let staged: Vec<u8> = (0..3).flat_map(|index| chunk_store::get("u1", index).unwrap()).collect();
+        assert_eq!(staged, b"abcdefg");
+        assert!(matches!(state.completed_upload(other, "u1", NOW), Err(DomainError::Forbidden(_))));
+
+        let expired = NOW + UPLOAD_SESSION_TIMEOUT * 1_000_000_000 + 1;
+        assert!(matches!(state.completed_upload(owner, "u1", expired), Err(DomainError::NotFound(_))));
+    }
+
+    #[test]
+    fn a_scoped_session_narrows_the_roles_of_every_call() {
+        let user = principal(1);
+        let mut state = State::default();
+        state.users.insert(user, User::new(user, "user".to_string(), vec![Role::Admin, Role::Publisher]));
+        let (full, _) = state.start_session(user, vec![Role::Admin, Role::Publisher], NOW);
+        assert_eq!(state.session_roles(user, NOW), Some(vec![Role::Admin, Role::Publisher]));
+
+        let (scoped, _) = state.start_session(user, vec![Role::Publisher], NOW);
+        assert_eq!(state.session_roles(user, NOW), Some(vec![Role::Publisher]));
+        assert!(!state.is_admin(user, NOW));
+
+        state.end_session(&scoped);
+        assert_eq!(state.session_roles(user, NOW), Some(vec![Role::Admin, Role::Publisher]));
+        state.end_session(&full);
+        assert_eq!(state.session_roles(user, NOW), None);
+    }
+}
@@ -0,0 +1,348 @@
+// Paged file listings. Pages are ordered by the requested sort key with the
+// file id as a tie breaker, and the cursor records the last entry returned,
+// so a page boundary stays put while files are added or removed.
+use crate::{FileMetadata, State};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use candid::{CandidType, Deserialize, Principal};
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+pub(crate) const DEFAULT_PAGE_SIZE: u32 = 50;
+pub(crate) const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SortBy {
+    #[default]
+    UploadedAt,
+    Size,
+    Filename,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub(crate) struct FileFilter {
+    pub uploader: Option<String>,
+    pub mime_type: Option<String>,
+    pub name_prefix: Option<String>,
+    pub file_hash: Option<String>,
+    pub uploaded_after: Option<u64>,
+    pub uploaded_before: Option<u64>,
+}
+
+impl FileFilter {
+    pub(crate) fn matches(&self, metadata: &FileMetadata) -> bool {
+        self.uploader.as_ref().is_none_or(|uploader| &metadata.owner_id == uploader)
+            && self.mime_type.as_ref().is_none_or(|mime_type| mime_matches(&metadata.mime_type, mime_type))
+            && self.name_prefix.as_ref().is_none_or(|prefix| metadata.filename.starts_with(prefix.as_str()))
+            && self.file_hash.as_ref().is_none_or(|file_hash| metadata.file_hash.as_ref() == Some(file_hash))
+            && self.uploaded_after.is_none_or(|after| metadata.uploaded_at > after)
+            && self.uploaded_before.is_none_or(|before| metadata.uploaded_at < before)
+    }
+}
+
+// A filter without parameters matches any parameters, so `text/html` also
+// finds `text/html; charset=utf-8`.
+fn mime_matches(mime_type: &str, filter: &str) -> bool {
+    if filter.contains(';') {
+        return mime_type.eq_ignore_ascii_case(filter);
+    }
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(filter.trim())
+}
+
+// Collects one page of the active files accepted by `filter` and `visible`,
+// plus the cursor for the next page if there is one. Sorting by upload time
+// walks the upload time index from the cursor and stops once the page is
+// full. Other orders take their candidates from the hash, owner or upload
+// time index, whichever the filter narrows best, and sort those.
+pub(crate) fn page<'a>(
+    state: &'a State,
+    filter: &FileFilter,
+    sort_by: SortBy,
+    order: SortOrder,
+    cursor: Option<&Cursor>,
+    limit: usize,
+    visible: impl Fn(&FileMetadata) -> bool,
+) -> (Vec<&'a FileMetadata>, Option<String>) {
+    let accept = |metadata: &&FileMetadata| filter.matches(metadata) && visible(metadata);
+    let lookup = |file_id: &String| state.files.get(file_id);
+
+    let narrowed: Option<Vec<&String>> = match (&filter.file_hash, &filter.uploader) {
+        (Some(file_hash), _) => Some(state.index.with_hash(file_hash).collect()),
+        (None, Some(uploader)) => Some(match Principal::from_text(uploader) {
+            Ok(owner) => state.index.owned_by(&owner).collect(),
+            Err(_) => Vec::new(),
+        }),
+        (None, None) => None,
+    };
+
+    let mut entries: Vec<&FileMetadata> = if let Some(candidates) = narrowed {
+        sorted_after(candidates.into_iter().filter_map(lookup).filter(accept), sort_by, order, cursor)
+    } else if sort_by == SortBy::UploadedAt {
+        let Some((mut lower, mut upper)) = upload_time_bounds(filter) else {
+            return (Vec::new(), None);
+        };
+        if let Some(Cursor { key: SortKey::Number(uploaded_at), file_id }) = cursor {
+            let position = (*uploaded_at, file_id.clone());
+            match order {
+                SortOrder::Asc => lower = Bound::Excluded(position),
+                SortOrder::Desc => upper = Bound::Excluded(position),
+            }
+        }
+        if is_empty_range(&lower, &upper) {
+            return (Vec::new(), None);
+        }
+        let range = state.index.uploaded((lower, upper)).map(|(_, file_id)| file_id);
+        let ordered: Box<dyn Iterator<Item = &String>> = match order {
+            SortOrder::Asc => Box::new(range),
+            SortOrder::Desc => Box::new(range.rev()),
+        };
+        ordered.filter_map(lookup).filter(accept).take(limit + 1).collect()
+    } else {
+        let Some((lower, upper)) = upload_time_bounds(filter) else {
+            return (Vec::new(), None);
+        };
+        if is_empty_range(&lower, &upper) {
+            return (Vec::new(), None);
+        }
+        let candidates = state.index.uploaded((lower, upper)).map(|(_, file_id)| file_id);
+        sorted_after(candidates.filter_map(lookup).filter(accept), sort_by, order, cursor)
+    };
+
+    let next_cursor = (entries.len() > limit)
+        .then(|| Cursor::at(entries[limit - 1], sort_by).encode(sort_by, order));
+    entries.truncate(limit);
+    (entries, next_cursor)
+}
+
+fn sorted_after<'a>(
+    entries: impl Iterator<Item = &'a FileMetadata>,
+    sort_by: SortBy,
+    order: SortOrder,
+    cursor: Option<&Cursor>,
+) -> Vec<&'a FileMetadata> {
+    let mut entries: Vec<(SortKey, &FileMetadata)> = entries
+        .map(|metadata| (SortKey::of(metadata, sort_by), metadata))
+        .filter(|(key, metadata)| cursor.is_none_or(|cursor| cursor.precedes(key, &metadata.id, order)))
+        .collect();
+    entries.sort_by(|(a, x), (b, y)| compare((a, &x.id), (b, &y.id), order));
+    entries.into_iter().map(|(_, metadata)| metadata).collect()
+}
+
+type UploadBound = Bound<(u64, String)>;
+
+// Upload time index bounds for the filter's date range, or `None` if nothing
+// can match. Ids sort after the empty string, so `(t, "")` sits just before
+// every file uploaded at `t`.
+fn upload_time_bounds(filter: &FileFilter) -> Option<(UploadBound, UploadBound)> {
+    let lower = match filter.uploaded_after {
+        Some(after) => Bound::Included((after.checked_add(1)?, String::new())),
+        None => Bound::Unbounded,
+    };
+    let upper = match filter.uploaded_before {
+        Some(before) => Bound::Excluded((before, String::new())),
+        None => Bound::Unbounded,
+    };
+    Some((lower, upper))
+}
+
+// `BTreeSet::range` panics on inverted ranges, so those are answered up front.
+fn is_empty_range<T: Ord>(lower: &Bound<T>, upper: &Bound<T>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum SortKey {
+    Number(u64),
+    Text(String),
+}
+
+impl SortKey {
+    pub(crate) fn of(metadata: &FileMetadata, sort_by: SortBy) -> Self {
+        match sort_by {
+            SortBy::UploadedAt => SortKey::Number(metadata.uploaded_at),
+            SortBy::Size => SortKey::Number(metadata.size),
+            SortBy::Filename => SortKey::Text(metadata.filename.clone()),
+        }
+    }
+}
+
+// Position of an entry in a listing: its sort key and file id.
+pub(crate) struct Cursor {
+    pub key: SortKey,
+    pub file_id: String,
+}
+
+impl Cursor {
+    pub(crate) fn at(metadata: &FileMetadata, sort_by: SortBy) -> Self {
+        Cursor {
+            key: SortKey::of(metadata, sort_by),
+            file_id: metadata.id.clone(),
+        }
+    }
+
+    // Whether the entry `(key, file_id)` comes after this cursor.
+    pub(crate) fn precedes(&self, key: &SortKey, file_id: &str, order: SortOrder) -> bool {
+        compare((&self.key, &self.file_id), (key, file_id), order) == Ordering::Less
+    }
+
+    // `{sort}{order}:{key}:{file id}`, base64 encoded. The sort and order are
+    // recorded so a cursor cannot be replayed against a different ordering.
+    pub(crate) fn encode(&self, sort_by: SortBy, order: SortOrder) -> String {
+        let key = match &self.key {
+            SortKey::Number(value) => value.to_string(),
+            SortKey::Text(value) => value.clone(),
+        };
+        let raw = format!("{}{}:{}:{}", sort_tag(sort_by), order_tag(order), key, self.file_id);
+        BASE64.encode(raw)
+    }
+
+    pub(crate) fn decode(cursor: &str, sort_by: SortBy, order: SortOrder) -> Result<Self, String> {
+        let invalid = || "Invalid cursor".to_string();
+        let raw = BASE64.decode(cursor).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(invalid)?;
+
+        let (tags, rest) = raw.split_once(':').ok_or_else(invalid)?;
+        if tags != format!("{}{}", sort_tag(sort_by), order_tag(order)) {
+            return Err("Cursor was issued for a different sort order".to_string());
+        }
+        let (key, file_id) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        let key = match sort_by {
+            SortBy::Filename => SortKey::Text(key.to_string()),
+            SortBy::UploadedAt | SortBy::Size => SortKey::Number(key.parse().map_err(|_| invalid())?),
+        };
+
+        Ok(Cursor {
+            key,
+            file_id: file_id.to_string(),
+        })
+    }
+}
+
+pub(crate) fn compare(a: (&SortKey, &str), b: (&SortKey, &str), order: SortOrder) -> Ordering {
+    match order {
+        SortOrder::Asc => a.cmp(&b),
+        SortOrder::Desc => b.cmp(&a),
+    }
+}
+
+fn sort_tag(sort_by: SortBy) -> char {
+    match sort_by {
+        SortBy::UploadedAt => 'u',
+        SortBy::Size => 's',
+        SortBy::Filename => 'f',
+    }
+}
+
+fn order_tag(order: SortOrder) -> char {
+    match order {
+        SortOrder::Asc => 'a',
+        SortOrder::Desc => 'd',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(files: &[(&str, u64)]) -> State {
+        let mut state = State::default();
+        for (id, uploaded_at) in files {
+            let metadata = FileMetadata::for_tests(id, &format!("/{}.txt", id), Principal::anonymous(), *uploaded_at);
+            state.index.insert(&metadata);
+            state.files.insert(id.to_string(), metadata);
+        }
+        state
+    }
+
+    // Follows the cursors through every page and returns the ids seen.
+    fn walk(state: &State, filter: &FileFilter, sort_by: SortBy, order: SortOrder, limit: usize) -> Vec<String> {
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (files, next) = page(state, filter, sort_by, order, cursor.as_ref(), limit, |_| true);
+            seen.extend(files.iter().map(|metadata| metadata.id.clone()));
+            match next {
+                Some(next) => cursor = Some(Cursor::decode(&next, sort_by, order).unwrap()),
+                None => return seen,
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips_filenames_with_colons() {
+        let cursor = Cursor {
+            key: SortKey::Text("logs:2024:01.txt".to_string()),
+            file_id: "abc".to_string(),
+        };
+        let decoded = Cursor::decode(&cursor.encode(SortBy::Filename, SortOrder::Asc), SortBy::Filename, SortOrder::Asc).unwrap();
+        assert!(decoded.key == cursor.key);
+        assert_eq!(decoded.file_id, "abc");
+    }
+
+    #[test]
+    fn cursor_is_refused_under_another_ordering() {
+        let cursor = Cursor {
+            key: SortKey::Number(5),
+            file_id: "abc".to_string(),
+        };
+        let encoded = cursor.encode(SortBy::UploadedAt, SortOrder::Asc);
+        assert!(Cursor::decode(&encoded, SortBy::UploadedAt, SortOrder::Desc).is_err());
+        assert!(Cursor::decode(&encoded, SortBy::Size, SortOrder::Asc).is_err());
+        assert!(Cursor::decode("not a cursor", SortBy::UploadedAt, SortOrder::Asc).is_err());
+    }
+
+    #[test]
+    fn desc_pages_over_equal_upload_times_without_gaps() {
+        let state = state_with(&[("a", 5), ("b", 5), ("c", 5), ("d", 4), ("e", 6)]);
+        let filter = FileFilter::default();
+        assert_eq!(walk(&state, &filter, SortBy::UploadedAt, SortOrder::Desc, 2), ["e", "c", "b", "a", "d"]);
+        assert_eq!(walk(&state, &filter, SortBy::UploadedAt, SortOrder::Asc, 2), ["d", "a", "b", "c", "e"]);
+        assert_eq!(walk(&state, &filter, SortBy::Size, SortOrder::Desc, 2), ["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn inverted_date_ranges_are_empty() {
+        let state = state_with(&[("a", 5), ("b", 10)]);
+        let filter = FileFilter {
+            uploaded_after: Some(10),
+            uploaded_before: Some(5),
+            ..Default::default()
+        };
+        for sort_by in [SortBy::UploadedAt, SortBy::Size] {
+            assert!(walk(&state, &filter, sort_by, SortOrder::Asc, 10).is_empty());
+        }
+        // Nothing lies strictly between consecutive times either.
+        let filter = FileFilter {
+            uploaded_after: Some(4),
+            uploaded_before: Some(5),
+            ..Default::default()
+        };
+        assert!(walk(&state, &filter, SortBy::UploadedAt, SortOrder::Desc, 10).is_empty());
+        let filter = FileFilter {
+            uploaded_after: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(walk(&state, &filter, SortBy::UploadedAt, SortOrder::Asc, 10).is_empty());
+
+        assert!(is_empty_range(&Bound::Included(2), &Bound::Included(1)));
+        assert!(is_empty_range(&Bound::Included(1), &Bound::Excluded(1)));
+        assert!(is_empty_range(&Bound::Excluded(1), &Bound::Included(1)));
+        assert!(!is_empty_range(&Bound::Included(1), &Bound::Included(1)));
+        assert!(!is_empty_range(&Bound::<u64>::Unbounded, &Bound::Excluded(0)));
+    }
+}
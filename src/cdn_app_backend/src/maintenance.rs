@@ -0,0 +1,64 @@
+// Periodic housekeeping. A canister timer runs `run` every INTERVAL to drop
+// what nothing else ever removes: expired sessions, abandoned uploads, files
+// deleted longer than DELETED_FILE_RETENTION ago, expired signed links and
+// idle rate limit buckets. Timers do not survive upgrades, so `start` is
+// called from both `init` and `post_upgrade`.
+use crate::{rate_limit, State, STATE};
+use candid::{CandidType, Deserialize};
+use ic_cdk::api;
+use serde::Serialize;
+use std::time::Duration;
+
+const INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+// How long the metadata of a deleted file is kept around.
+pub(crate) const DELETED_FILE_RETENTION: u64 = 30 * 24 * 60 * 60; // 30 days in seconds
+
+#[derive(Clone, Copy, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct Purged {
+    pub sessions: u64,
+    pub uploads: u64,
+    pub deleted_files: u64,
+    pub signed_links: u64,
+    pub rate_limit_buckets: u64,
+}
+
+impl Purged {
+    fn add(&mut self, other: &Purged) {
+        self.sessions += other.sessions;
+        self.uploads += other.uploads;
+        self.deleted_files += other.deleted_files;
+        self.signed_links += other.signed_links;
+        self.rate_limit_buckets += other.rate_limit_buckets;
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct MaintenanceStatus {
+    pub last_run_at: Option<u64>,
+    pub runs: u64,
+    // What the last run purged, and everything purged since the canister was
+    // installed.
+    pub last_purged: Purged,
+    pub total_purged: Purged,
+}
+
+pub(crate) fn start() {
+    ic_cdk_timers::set_timer_interval(INTERVAL, || {
+        STATE.with(|state| run(&mut state.borrow_mut(), api::time()));
+    });
+}
+
+pub(crate) fn run(state: &mut State, now: u64) {
+    let purged = Purged {
+        sessions: state.purge_expired_sessions(now),
+        uploads: state.purge_expired_uploads(now),
+        deleted_files: state.purge_deleted_files(now),
+        signed_links: state.purge_expired_signed_links(now),
+        rate_limit_buckets: rate_limit::prune(now),
+    };
+    let status = &mut state.maintenance;
+    status.last_run_at = Some(now);
+    status.runs += 1;
+    status.last_purged = purged;
+    status.total_purged.add(&purged);
+}
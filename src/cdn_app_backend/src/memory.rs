@@ -0,0 +1,25 @@
+// Stable memory layout. Every stable structure gets its own virtual memory
+// from the memory manager so they can grow independently.
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
+use std::cell::RefCell;
+
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES: MemoryId = MemoryId::new(0);
+const CHUNKS: MemoryId = MemoryId::new(1);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+// Holds the heap state snapshot written in pre_upgrade.
+pub(crate) fn upgrades_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADES))
+}
+
+// Backs the file chunk B-tree in `chunk_store`.
+pub(crate) fn chunks_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNKS))
+}
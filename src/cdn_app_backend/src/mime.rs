@@ -0,0 +1,153 @@
+// Content type resolution for uploads: explicit type, then the extension
+// table from `Config`, then magic-byte sniffing of the first chunk.
+use std::collections::BTreeMap;
+
+pub(crate) const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+const MAX_MIME_TYPE_LEN: usize = 255;
+
+pub(crate) fn default_table() -> BTreeMap<String, String> {
+    [
+        ("html", "text/html; charset=utf-8"),
+        ("htm", "text/html; charset=utf-8"),
+        ("css", "text/css; charset=utf-8"),
+        ("js", "text/javascript; charset=utf-8"),
+        ("mjs", "text/javascript; charset=utf-8"),
+        ("json", "application/json"),
+        ("map", "application/json"),
+        ("txt", "text/plain; charset=utf-8"),
+        ("md", "text/markdown; charset=utf-8"),
+        ("csv", "text/csv; charset=utf-8"),
+        ("xml", "application/xml"),
+        ("svg", "image/svg+xml"),
+        ("png", "image/png"),
+        ("jpg", "image/jpeg"),
+        ("jpeg", "image/jpeg"),
+        ("gif", "image/gif"),
+        ("webp", "image/webp"),
+        ("avif", "image/avif"),
+        ("ico", "image/x-icon"),
+        ("bmp", "image/bmp"),
+        ("woff", "font/woff"),
+        ("woff2", "font/woff2"),
+        ("ttf", "font/ttf"),
+        ("otf", "font/otf"),
+        ("pdf", "application/pdf"),
+        ("zip", "application/zip"),
+        ("gz", "application/gzip"),
+        ("wasm", "application/wasm"),
+        ("mp4", "video/mp4"),
+        ("webm", "video/webm"),
+        ("mp3", "audio/mpeg"),
+        ("wav", "audio/wav"),
+    ]
+    .into_iter()
+    .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+    .collect()
+}
+
+// Rejects values that cannot be sent back as a `Content-Type` header.
+pub(crate) fn validate(content_type: &str) -> Result<(), String> {
+    let content_type = content_type.trim();
+    if content_type.is_empty() || content_type.len() > MAX_MIME_TYPE_LEN {
+        return Err("Content type must be between 1 and 255 characters".to_string());
+    }
+    if !content_type.contains('/') || content_type.chars().any(|c| c.is_control()) {
+        return Err(format!("Invalid content type: {}", content_type));
+    }
+    Ok(())
+}
+
+pub(crate) fn normalize_extension(extension: &str) -> String {
+    extension.trim().trim_start_matches('.').to_ascii_lowercase()
+}
+
+pub(crate) fn resolve(
+    explicit: Option<&str>,
+    filename: &str,
+    first_chunk: &[u8],
+    table: &BTreeMap<String, String>,
+) -> String {
+    if let Some(content_type) = explicit {
+        return content_type.trim().to_string();
+    }
+
+    let by_extension = filename
+        .rsplit_once('.')
+        .and_then(|(_, ext)| table.get(&normalize_extension(ext)));
+    if let Some(content_type) = by_extension {
+        return content_type.clone();
+    }
+
+    sniff(first_chunk).unwrap_or(DEFAULT_MIME_TYPE).to_string()
+}
+
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"\0\0\x01\0", "image/x-icon"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(mime);
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" {
+        match &data[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    // "BM" alone would catch plain text; the file header's reserved bytes are zero.
+    if data.len() >= 14 && data.starts_with(b"BM") && data[6..10] == [0; 4] {
+        return Some("image/bmp");
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return Some(if &data[8..12] == b"avif" { "image/avif" } else { "video/mp4" });
+    }
+
+    let head = String::from_utf8_lossy(&data[..data.len().min(256)]).trim_start().to_ascii_lowercase();
+    if head.starts_with("<!doctype html") || head.starts_with("<html") {
+        return Some("text/html; charset=utf-8");
+    }
+    if head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg")) {
+        return Some("image/svg+xml");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_bmp_only_with_a_bitmap_file_header() {
+        let mut bitmap = b"BM".to_vec();
+        bitmap.extend_from_slice(&70u32.to_le_bytes());
+        bitmap.extend_from_slice(&[0; 4]);
+        bitmap.extend_from_slice(&54u32.to_le_bytes());
+        assert_eq!(sniff(&bitmap), Some("image/bmp"));
+
+        assert_eq!(sniff(b"BMW report for the third quarter"), None);
+        assert_eq!(sniff(b"BM"), None);
+    }
+
+    #[test]
+    fn resolve_prefers_explicit_type_then_extension() {
+        let table = default_table();
+        assert_eq!(resolve(Some(" text/plain "), "a.png", b"", &table), "text/plain");
+        assert_eq!(resolve(None, "notes.TXT", b"\x89PNG\r\n\x1a\n", &table), "text/plain; charset=utf-8");
+        assert_eq!(resolve(None, "report", b"BMW report", &table), DEFAULT_MIME_TYPE);
+        assert_eq!(resolve(None, "image", b"\x89PNG\r\n\x1a\n", &table), "image/png");
+    }
+}
@@ -0,0 +1,157 @@
+// Hierarchical path namespace. Files are published at absolute, slash
+// separated paths such as `/site-a/js/app.js`; directories are implicit and
+// exist for as long as some file lives below them.
+use std::collections::{BTreeMap, BTreeSet};
+
+const MAX_PATH_LEN: usize = 1024;
+// `/files/{id}` addresses files by id over HTTP, so nothing may be published
+// underneath it.
+const RESERVED_ROOT: &str = "files";
+
+pub(crate) fn normalize(filename: &str) -> String {
+    format!("/{}", filename.trim_start_matches('/'))
+}
+
+// Normalizes `filename` into a path and rejects anything that cannot be
+// addressed unambiguously.
+pub(crate) fn validate(filename: &str) -> Result<String, String> {
+    let path = normalize(filename);
+    if path.len() > MAX_PATH_LEN {
+        return Err(format!("Path must be at most {} bytes", MAX_PATH_LEN));
+    }
+    if path.ends_with('/') {
+        return Err(format!("Path must name a file, not a directory: {}", path));
+    }
+    for segment in path[1..].split('/') {
+        if segment.is_empty() || segment == "." || segment == ".." {
+            return Err(format!("Invalid path segment in {}", path));
+        }
+        if segment.chars().any(|c| c.is_control()) {
+            return Err(format!("Path contains control characters: {}", path));
+        }
+    }
+    if path[1..].split('/').next() == Some(RESERVED_ROOT) && path.matches('/').count() > 1 {
+        return Err(format!("Paths under /{}/ are reserved", RESERVED_ROOT));
+    }
+    Ok(path)
+}
+
+// A path that is not published yet may only be taken if it neither sits below
+// an existing file nor would turn an existing directory into a file.
+pub(crate) fn check_available(paths: &BTreeMap<String, String>, path: &str) -> Result<(), String> {
+    if let Some(file) = ancestors(path).find(|ancestor| paths.contains_key(*ancestor)) {
+        return Err(format!("{} is a file, not a directory", file));
+    }
+    let directory = format!("{}/", path);
+    if paths.range(directory.clone()..).next().is_some_and(|(key, _)| key.starts_with(&directory)) {
+        return Err(format!("{} is a directory", path));
+    }
+    Ok(())
+}
+
+// Directories containing `path`, nearest first, ending with the root `/`.
+pub(crate) fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.rmatch_indices('/')
+        .filter(|(index, _)| *index > 0)
+        .map(move |(index, _)| &path[..index])
+        .chain(std::iter::once("/"))
+}
+
+// Normalizes a directory path: `/site-a/js/` and `site-a/js` both become
+// `/site-a/js`, and the root is `/`.
+pub(crate) fn normalize_directory(path: &str) -> String {
+    normalize(path.trim_end_matches('/'))
+}
+
+pub(crate) struct Listing<'a> {
+    pub folders: BTreeSet<String>,
+    pub file_ids: Vec<&'a String>,
+}
+
+// Lists the paths starting with `prefix` the way S3 does: any path with
+// `delimiter` in the remainder after the prefix is rolled up into a folder
+// ending at that delimiter. An empty delimiter lists every path below the
+// prefix. Only paths accepted by `visible` are considered, so folders holding
+// nothing the caller may see are not revealed.
+pub(crate) fn list<'a>(
+    paths: &'a BTreeMap<String, String>,
+    prefix: &str,
+    delimiter: &str,
+    visible: impl Fn(&String) -> bool,
+) -> Listing<'a> {
+    let mut listing = Listing {
+        folders: BTreeSet::new(),
+        file_ids: Vec::new(),
+    };
+
+    let entries = paths.range(prefix.to_string()..)
+        .take_while(|(path, _)| path.starts_with(prefix))
+        .filter(|(_, file_id)| visible(file_id));
+    for (path, file_id) in entries {
+        let rest = &path[prefix.len()..];
+        match rest.find(delimiter).filter(|_| !delimiter.is_empty()) {
+            Some(index) => {
+                listing.folders.insert(path[..prefix.len() + index + delimiter.len()].to_string());
+            }
+            None => listing.file_ids.push(file_id),
+        }
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn published(paths: &[&str]) -> BTreeMap<String, String> {
+        paths.iter().enumerate().map(|(i, path)| (path.to_string(), format!("f{}", i))).collect()
+    }
+
+    #[test]
+    fn validate_normalizes_and_rejects_ambiguous_paths() {
+        assert_eq!(validate("site-a/app.js").as_deref(), Ok("/site-a/app.js"));
+        assert_eq!(validate("/site-a/app.js").as_deref(), Ok("/site-a/app.js"));
+        assert_eq!(validate("files").as_deref(), Ok("/files"));
+        for invalid in ["", "/", "site-a/", "a//b", "a/./b", "a/../b", "a/b\n", "files/x", "/files/abc/d"] {
+            assert!(validate(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+        assert!(validate(&"a".repeat(MAX_PATH_LEN)).is_err());
+    }
+
+    #[test]
+    fn check_available_keeps_files_and_directories_apart() {
+        let paths = published(&["/site/app.js", "/site/js/lib.js"]);
+        assert!(check_available(&paths, "/site/index.html").is_ok());
+        assert!(check_available(&paths, "/site/app.js/inner").is_err());
+        assert!(check_available(&paths, "/site/js").is_err());
+        assert!(check_available(&paths, "/site").is_err());
+        // A shared name prefix is not a directory.
+        assert!(check_available(&paths, "/site/j").is_ok());
+        assert!(check_available(&paths, "/sit").is_ok());
+    }
+
+    #[test]
+    fn list_rolls_up_folders_at_the_delimiter() {
+        let paths = published(&["/a.txt", "/site/app.js", "/site/js/lib.js", "/site/js/util.js", "/sitemap.xml"]);
+
+        let listing = list(&paths, "/site/", "/", |_| true);
+        assert_eq!(listing.folders.into_iter().collect::<Vec<_>>(), ["/site/js/"]);
+        assert_eq!(listing.file_ids, ["f1"]);
+
+        let listing = list(&paths, "/", "/", |_| true);
+        assert_eq!(listing.folders.into_iter().collect::<Vec<_>>(), ["/site/"]);
+        assert_eq!(listing.file_ids, ["f0", "f4"]);
+
+        let listing = list(&paths, "/site", "", |_| true);
+        assert!(listing.folders.is_empty());
+        assert_eq!(listing.file_ids, ["f1", "f2", "f3", "f4"]);
+    }
+
+    #[test]
+    fn list_hides_folders_without_visible_files() {
+        let paths = published(&["/public/a.txt", "/secret/b.txt"]);
+        let listing = list(&paths, "/", "/", |file_id| file_id == "f0");
+        assert_eq!(listing.folders.into_iter().collect::<Vec<_>>(), ["/public/"]);
+        assert!(listing.file_ids.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+// Permission matrix: who may call each update method. Every update method is
+// listed here and checked both in `inspect_message` and again by the method
+// itself, since calls from other canisters skip inspection. A method missing
+// from the matrix is refused, so a new endpoint stays closed until it is
+// added. Per-file ACLs are checked on top of this by the methods themselves.
+use crate::{DomainError, Role};
+use candid::Principal;
+
+pub(crate) enum Access {
+    // Anyone, including the anonymous principal.
+    Anyone,
+    // Any caller that is not anonymous.
+    Identified,
+    // Any caller with a live session.
+    SignedIn,
+    // Callers holding one of these roles.
+    Roles(&'static [Role]),
+}
+
+const PUBLISHERS: &[Role] = &[Role::Admin, Role::Publisher];
+const ADMINS: &[Role] = &[Role::Admin];
+
+const MATRIX: &[(&str, Access)] = &[
+    // Sessions
+    ("register", Access::Identified),
+    ("login", Access::Identified),
+    ("logout", Access::Identified),
+    // Users
+    ("update_profile", Access::Identified),
+    ("deactivate_user", Access::Roles(ADMINS)),
+    ("reactivate_user", Access::Roles(ADMINS)),
+    // Uploads
+    ("upload_file", Access::Roles(PUBLISHERS)),
+    ("begin_upload", Access::Roles(PUBLISHERS)),
+    ("put_chunk", Access::Roles(PUBLISHERS)),
+    ("commit_upload", Access::Roles(PUBLISHERS)),
+    ("abort_upload", Access::Roles(PUBLISHERS)),
+    ("upload_encoding", Access::Roles(PUBLISHERS)),
+    ("begin_encoding_upload", Access::Roles(PUBLISHERS)),
+    // Files; who may change a file is up to its ACL.
+    ("rollback_file", Access::SignedIn),
+    ("move_file", Access::SignedIn),
+    ("rename_file", Access::SignedIn),
+    ("delete_file", Access::SignedIn),
+    ("set_file_acl", Access::SignedIn),
+    ("set_file_visibility", Access::SignedIn),
+    ("set_directory_visibility", Access::Roles(ADMINS)),
+    ("wipe_all", Access::Roles(ADMINS)),
+    // Reads that need an update call; the link token is the credential.
+    ("get_file_signed", Access::Anyone),
+    ("http_request_update", Access::Anyone),
+    // Signed links
+    ("create_signed_url", Access::SignedIn),
+    ("revoke_signed_url", Access::SignedIn),
+    // Roles
+    ("grant_role", Access::Roles(ADMINS)),
+    ("revoke_role", Access::Roles(ADMINS)),
+    ("propose_admin_transfer", Access::Roles(ADMINS)),
+    ("cancel_admin_transfer", Access::Roles(ADMINS)),
+    // The recipient is not an admin yet.
+    ("accept_admin_transfer", Access::Identified),
+    // Configuration
+    ("update_config", Access::Roles(ADMINS)),
+    ("set_mime_type", Access::Roles(ADMINS)),
+    ("remove_mime_type", Access::Roles(ADMINS)),
+    ("reset_config", Access::Roles(ADMINS)),
+    ("set_rate_limit", Access::Roles(ADMINS)),
+    // Quotas
+    ("set_user_quota", Access::Roles(ADMINS)),
+    ("set_role_quota", Access::Roles(ADMINS)),
+];
+
+pub(crate) fn access(method: &str) -> Option<&'static Access> {
+    MATRIX.iter()
+        .find(|(listed, _)| *listed == method)
+        .map(|(_, access)| access)
+}
+
+// `roles` are those of the caller's live sessions, `None` if they have none.
+pub(crate) fn check(method: &str, caller: Principal, roles: Option<&[Role]>) -> Result<(), DomainError> {
+    let access = access(method)
+        .ok_or_else(|| DomainError::Forbidden(format!("{} is not open to callers", method)))?;
+    if matches!(access, Access::Anyone) {
+        return Ok(());
+    }
+    if caller == Principal::anonymous() {
+        return Err(DomainError::Unauthorized(format!("{} requires signing in", method)));
+    }
+    if matches!(access, Access::Identified) {
+        return Ok(());
+    }
+    let roles = roles
+        .ok_or_else(|| DomainError::Unauthorized(format!("{} requires an active session, call login first", method)))?;
+    match access {
+        Access::Roles(required) if !required.iter().any(|role| roles.contains(role)) => {
+            Err(DomainError::Forbidden(format!("{} requires one of the roles {:?}", method, required)))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,54 @@
+// Storage quotas. Usage is charged to the owner of a file and covers every
+// stored revision and encoding, whether or not the bytes are shared with
+// other files through the blob store.
+use crate::{FileMetadata, Role};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+// `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Quota {
+    // The more generous of the two limits for each resource.
+    pub(crate) fn max(self, other: Quota) -> Quota {
+        let pick = |a: Option<u64>, b: Option<u64>| a.zip(b).map(|(a, b)| a.max(b));
+        Quota {
+            max_bytes: pick(self.max_bytes, other.max_bytes),
+            max_files: pick(self.max_files, other.max_files),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct Usage {
+    pub principal: String,
+    pub used_bytes: u64,
+    pub used_files: u64,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+pub(crate) fn default_role_quotas() -> Vec<(Role, Quota)> {
+    vec![
+        (Role::Admin, Quota::default()),
+        (Role::Publisher, Quota {
+            max_bytes: Some(1024 * 1024 * 1024),
+            max_files: Some(10_000),
+        }),
+        (Role::Viewer, Quota {
+            max_bytes: Some(50 * 1024 * 1024),
+            max_files: Some(100),
+        }),
+    ]
+}
+
+// Bytes held by a file across all of its revisions and encodings.
+pub(crate) fn stored_bytes(metadata: &FileMetadata) -> u64 {
+    metadata.revisions.iter()
+        .map(|revision| revision.size + revision.encodings.values().map(|e| e.size).sum::<u64>())
+        .sum()
+}
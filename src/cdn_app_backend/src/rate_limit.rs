@@ -0,0 +1,153 @@
+// Token-bucket rate limiting per (caller, method). Each bucket holds up to
+// `capacity` calls and refills continuously at `refill_per_minute`. Buckets
+// live on the heap only: an upgrade hands everyone a full bucket again.
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Tokens are tracked in thousandths so slow refill rates still accrue
+// between calls.
+const SCALE: u64 = 1_000;
+const NANOS_PER_MINUTE: u128 = 60 * 1_000_000_000;
+// Buckets idle for an hour are dropped by maintenance, and by `take` once
+// there are more than PRUNE_THRESHOLD of them. With any practical refill rate
+// they are full again by then.
+const PRUNE_THRESHOLD: usize = 10_000;
+const IDLE_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+struct Bucket {
+    tokens: u64,
+    last_refill: u64,
+}
+
+thread_local! {
+    static BUCKETS: RefCell<HashMap<(Principal, String), Bucket>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn default_limits() -> Vec<(String, RateLimit)> {
+    [
+        ("register", 5, 5),
+        ("login", 10, 10),
+        ("upload_file", 30, 30),
+        ("begin_upload", 30, 30),
+        ("begin_encoding_upload", 30, 30),
+        ("upload_encoding", 30, 30),
+        ("put_chunk", 600, 600),
+        ("commit_upload", 30, 30),
+        ("create_signed_url", 30, 30),
+    ]
+    .into_iter()
+    .map(|(method, capacity, refill_per_minute)| (method.to_string(), RateLimit { capacity, refill_per_minute }))
+    .collect()
+}
+
+pub(crate) fn validate(limit: &RateLimit) -> Result<(), String> {
+    if limit.capacity == 0 {
+        return Err("Rate limit capacity must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// Whether `caller` has a call to `method` left, without using it up.
+pub(crate) fn allows(caller: Principal, method: &str, limit: &RateLimit, now: u64) -> bool {
+    BUCKETS.with(|buckets| {
+        let buckets = buckets.borrow();
+        match buckets.get(&(caller, method.to_string())) {
+            Some(bucket) => refilled(bucket, limit, now) >= SCALE,
+            None => true,
+        }
+    })
+}
+
+// Uses up one call of `caller` to `method`. Returns false if none is left.
+pub(crate) fn take(caller: Principal, method: &str, limit: &RateLimit, now: u64) -> bool {
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        if buckets.len() > PRUNE_THRESHOLD {
+            prune_idle(&mut buckets, now);
+        }
+
+        let capacity = SCALE * u64::from(limit.capacity);
+        let bucket = buckets.entry((caller, method.to_string())).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        bucket.tokens = refilled(bucket, limit, now);
+        bucket.last_refill = now;
+        if bucket.tokens < SCALE {
+            return false;
+        }
+        bucket.tokens -= SCALE;
+        true
+    })
+}
+
+// Drops idle buckets, returning how many were dropped.
+pub(crate) fn prune(now: u64) -> u64 {
+    BUCKETS.with(|buckets| prune_idle(&mut buckets.borrow_mut(), now))
+}
+
+fn prune_idle(buckets: &mut HashMap<(Principal, String), Bucket>, now: u64) -> u64 {
+    let before = buckets.len();
+    buckets.retain(|_, bucket| now.saturating_sub(bucket.last_refill) < IDLE_NANOS);
+    (before - buckets.len()) as u64
+}
+
+fn refilled(bucket: &Bucket, limit: &RateLimit, now: u64) -> u64 {
+    let elapsed = u128::from(now.saturating_sub(bucket.last_refill));
+    let refill = elapsed * u128::from(limit.refill_per_minute) * u128::from(SCALE) / NANOS_PER_MINUTE;
+    let capacity = SCALE * u64::from(limit.capacity);
+    u64::try_from(u128::from(bucket.tokens) + refill).unwrap_or(u64::MAX).min(capacity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    const LIMIT: RateLimit = RateLimit { capacity: 2, refill_per_minute: 6 };
+
+    #[test]
+    fn take_empties_the_bucket_and_refills_over_time() {
+        let caller = Principal::anonymous();
+        assert!(take(caller, "login", &LIMIT, 0));
+        assert!(take(caller, "login", &LIMIT, 0));
+        assert!(!take(caller, "login", &LIMIT, 0));
+        assert!(!allows(caller, "login", &LIMIT, 0));
+
+        // One call comes back every 10 seconds, in fractions until then.
+        assert!(!take(caller, "login", &LIMIT, 9 * SECOND));
+        assert!(allows(caller, "login", &LIMIT, 10 * SECOND));
+        assert!(take(caller, "login", &LIMIT, 10 * SECOND));
+        assert!(!take(caller, "login", &LIMIT, 10 * SECOND));
+    }
+
+    #[test]
+    fn refill_stops_at_capacity() {
+        let caller = Principal::anonymous();
+        assert!(take(caller, "login", &LIMIT, 0));
+        let later = 60 * 60 * SECOND;
+        assert!(take(caller, "login", &LIMIT, later));
+        assert!(take(caller, "login", &LIMIT, later));
+        assert!(!take(caller, "login", &LIMIT, later));
+    }
+
+    #[test]
+    fn buckets_are_kept_per_caller_and_method() {
+        let (alice, bob) = (Principal::from_slice(&[1; 29]), Principal::from_slice(&[2; 29]));
+        let single = RateLimit { capacity: 1, refill_per_minute: 0 };
+        assert!(take(alice, "login", &single, 0));
+        assert!(!take(alice, "login", &single, 0));
+        assert!(take(alice, "register", &single, 0));
+        assert!(take(bob, "login", &single, 0));
+        assert_eq!(prune(IDLE_NANOS), 3);
+        assert!(take(alice, "login", &single, IDLE_NANOS));
+    }
+}
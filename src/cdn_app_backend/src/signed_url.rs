@@ -0,0 +1,108 @@
+// Signed links: bearer tokens that grant read access to a single file until
+// they expire, run out of uses or are revoked. Only a hash of each token is
+// stored, so the token itself is shown once, when the link is created.
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub(crate) const MAX_TTL_SECS: u64 = 30 * 24 * 60 * 60; // 30 days
+// Long enough for the gateway to fetch every chunk of one response.
+pub(crate) const STREAM_GRANT_TTL_SECS: u64 = 10 * 60; // 10 minutes
+// Redeemed bodies up to this size go out whole; leaves the rest of the reply
+// limit to the headers.
+pub(crate) const MAX_INLINE_BODY: u64 = 1536 * 1024; // 1.5 MiB
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SignedLink {
+    pub file_id: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+impl SignedLink {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.max_uses.is_some_and(|max_uses| self.uses >= max_uses)
+    }
+
+    // Use-limited links can only be redeemed in update calls, where the use
+    // count change is kept.
+    pub(crate) fn is_use_limited(&self) -> bool {
+        self.max_uses.is_some()
+    }
+}
+
+// Lets the streaming callback serve the remaining chunks of one response
+// that redeemed a use of a use-limited link. The callback is a query and
+// cannot count uses, so it never accepts such a link itself, nor spend the
+// grant: only bodies too large for one reply are given one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StreamGrant {
+    pub link_id: String,
+    pub file_id: String,
+    pub content_encoding: String,
+    // Grants from before this field never match and lapse.
+    #[serde(default)]
+    pub file_hash: String,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SignedUrl {
+    pub id: String,
+    pub token: String,
+    pub url: String,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SignedUrlInfo {
+    pub id: String,
+    pub file_id: String,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+impl SignedUrlInfo {
+    pub(crate) fn new(id: &str, link: &SignedLink) -> Self {
+        SignedUrlInfo {
+            id: id.to_string(),
+            file_id: link.file_id.clone(),
+            created_by: link.created_by.to_string(),
+            created_at: link.created_at,
+            expires_at: link.expires_at,
+            max_uses: link.max_uses,
+            uses: link.uses,
+        }
+    }
+}
+
+// Links are stored under the hash of their token.
+pub(crate) fn link_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Mixes the secret link token into the id, so the grants of a link cannot be
+// guessed from the counter and time alone.
+pub(crate) fn grant_id(token: &str, nonce: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", token, nonce).as_bytes()))
+}
+
+pub(crate) fn validate(ttl_secs: u64, max_uses: Option<u32>) -> Result<(), String> {
+    if ttl_secs == 0 || ttl_secs > MAX_TTL_SECS {
+        return Err(format!("Link lifetime must be between 1 and {} seconds", MAX_TTL_SECS));
+    }
+    if max_uses == Some(0) {
+        return Err("Maximum uses must be at least 1".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,602 @@
+// Versioned state snapshot written to stable memory across upgrades.
+//
+// Layout of the upgrades memory:
+//   [0..4)   magic "CDNS"
+//   [4..8)   snapshot version (u32, little endian)
+//   [8..16)  payload length in bytes (u64, little endian)
+//   [16..)   CBOR encoded `State`
+//
+// The payload is streamed straight into stable memory, so saving never needs
+// a second copy of the whole state on the heap.
+//
+// Version history:
+//   1 - chunk payloads inside the snapshot
+//   2 - chunk payloads in `chunk_store`, only metadata in the snapshot
+//   3 - content-addressed blobs shared between files with the same hash
+//   4 - files addressed by path with a history of revisions
+//   5 - secondary indexes over the active files
+//   6 - per-file ACLs replace `roles_allowed`; every file had all roles
+//       allowed, which is exactly what the default ACL grants
+//   7 - sessions indexed by principal
+//   8 - one record per principal: the `roles` map is merged into `users`
+//   9 - brotli encodings dropped
+use crate::{chunk_store, encoding, memory, paths};
+use crate::index::FileIndex;
+use crate::{FileRevision, State, StoredBlob, User};
+use ic_stable_structures::reader::{BufferedReader, Reader};
+use ic_stable_structures::writer::{BufferedWriter, Writer};
+use ic_stable_structures::Memory as _;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CDNS";
+const STATE_VERSION: u32 = 9;
+const HEADER_LEN: u64 = 16;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Counts the bytes passing through so the payload length can be recorded
+// in the header once streaming has finished.
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn save_state(state: &State) -> Result<(), String> {
+    let mut memory = memory::upgrades_memory();
+
+    let payload_len = {
+        let writer = Writer::new(&mut memory, HEADER_LEN);
+        let mut writer = CountingWriter {
+            inner: BufferedWriter::new(BUFFER_SIZE, writer),
+            written: 0,
+        };
+        serde_cbor::to_writer(&mut writer, state)
+            .map_err(|e| format!("failed to encode state: {}", e))?;
+        writer.flush()
+            .map_err(|e| format!("failed to write state: {}", e))?;
+        writer.written
+    };
+
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    header.extend_from_slice(&payload_len.to_le_bytes());
+    memory.write(0, &header);
+    Ok(())
+}
+
+// Returns `Ok(None)` when no snapshot was ever written (fresh install or an
+// upgrade from a release without upgrade hooks). Anything else that cannot be
+// decoded is an error, never an empty state.
+pub(crate) fn load_state() -> Result<Option<State>, String> {
+    let memory = memory::upgrades_memory();
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    memory.read(0, &mut header);
+    if &header[0..4] != MAGIC {
+        return Err("stable memory does not contain a state snapshot".to_string());
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let payload_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+    let reader = BufferedReader::new(BUFFER_SIZE, Reader::new(&memory, HEADER_LEN));
+    decode(version, reader.take(payload_len)).map(Some)
+}
+
+fn decode<R: Read>(version: u32, reader: R) -> Result<State, String> {
+    let mut state = match version {
+        1 => serde_cbor::from_reader(reader).map(v1::State::migrate),
+        2..=STATE_VERSION => serde_cbor::from_reader(reader),
+        other => {
+            return Err(format!(
+                "unsupported state snapshot version {} (this build reads up to {})",
+                other, STATE_VERSION
+            ))
+        }
+    }
+    .map_err(|e| format!("failed to decode state v{}: {}", version, e))?;
+
+    if version < 3 {
+        index_blobs(&mut state);
+    }
+    if version < 4 {
+        index_paths(&mut state);
+    }
+    if version < 5 {
+        state.index = FileIndex::build(&state.files);
+    }
+    if version < 7 {
+        for (session_id, session) in &state.sessions {
+            state.user_sessions.entry(session.user_id).or_default().insert(session_id.clone());
+        }
+    }
+    if version < 8 {
+        merge_roles(&mut state);
+    }
+    if version < 9 {
+        drop_brotli(&mut state);
+    }
+    check_invariants(&state)?;
+    Ok(state)
+}
+
+// Before v4 files had no revisions and several files could share a filename.
+// Each file becomes revision 1 of its path; when names collide the newest
+// active file keeps the plain path and the others move under their id.
+fn index_paths(state: &mut State) {
+    let mut files: Vec<_> = state.files.values_mut().collect();
+    files.sort_by_key(|m| std::cmp::Reverse((m.is_active, m.uploaded_at)));
+
+    for metadata in files {
+        let path = paths::normalize(&metadata.filename);
+        metadata.path = if metadata.is_active && !state.paths.contains_key(&path) {
+            state.paths.insert(path.clone(), metadata.id.clone());
+            path
+        } else {
+            format!("/{}{}", metadata.id, path)
+        };
+
+        metadata.current_revision = 1;
+        metadata.revisions = vec![FileRevision {
+            revision: 1,
+            size: metadata.size,
+            chunk_count: metadata.chunk_count,
+            chunk_sizes: metadata.chunk_sizes.clone(),
+            file_hash: metadata.file_hash.clone().unwrap_or_default(),
+            mime_type: metadata.mime_type.clone(),
+            encodings: metadata.encodings.clone(),
+            uploaded_by: metadata.owner,
+            uploaded_at: metadata.uploaded_at,
+        }];
+    }
+}
+
+// Before v8 roles lived in their own map next to `users`, and the `role` on
+// each user was never kept up to date. The map wins; principals that only had
+// roles, such as the admin set up by `init`, get a record of their own.
+fn merge_roles(state: &mut State) {
+    for (principal, roles) in std::mem::take(&mut state.legacy_roles) {
+        let user = state.users.entry(principal)
+            .or_insert_with(|| User::placeholder(principal));
+        user.roles.clear();
+        for role in roles {
+            if !user.roles.contains(&role) {
+                user.roles.push(role);
+            }
+        }
+    }
+}
+
+// Before v9 brotli encodings were accepted, but certified query responses
+// could never serve them. Drop them with the uploads still sending one.
+fn drop_brotli(state: &mut State) {
+    let mut released = Vec::new();
+    for metadata in state.files.values_mut() {
+        metadata.encodings.remove(encoding::BROTLI);
+        for revision in &mut metadata.revisions {
+            let dropped = revision.encodings.remove(encoding::BROTLI);
+            // Deleting a file already released the blobs of its revisions.
+            if let Some(dropped) = dropped.filter(|_| metadata.is_active) {
+                released.push(dropped.file_hash);
+            }
+        }
+    }
+    for file_hash in released {
+        state.release_blob(&file_hash);
+    }
+    state.uploads.retain(|upload_id, upload| {
+        let brotli = upload.target.as_ref().is_some_and(|(_, content_encoding)| content_encoding == encoding::BROTLI);
+        if brotli {
+            chunk_store::remove_all(upload_id);
+        }
+        !brotli
+    });
+}
+
+// A snapshot that breaks these is refused, which rolls the upgrade back
+// rather than running on inconsistent state.
+fn check_invariants(state: &State) -> Result<(), String> {
+    for (principal, user) in &state.users {
+        if user.id != principal.to_text() {
+            return Err(format!("user record {} is stored under {}", user.id, principal));
+        }
+        if user.roles.iter().enumerate().any(|(i, role)| user.roles[..i].contains(role)) {
+            return Err(format!("user {} holds a role twice", principal));
+        }
+    }
+    for (session_id, session) in &state.sessions {
+        let user = state.users.get(&session.user_id)
+            .ok_or_else(|| format!("session {} belongs to unknown user {}", session_id, session.user_id))?;
+        if !user.is_active {
+            return Err(format!("deactivated user {} has session {}", session.user_id, session_id));
+        }
+        if !state.user_sessions.get(&session.user_id).is_some_and(|ids| ids.contains(session_id)) {
+            return Err(format!("session {} is missing from the session index", session_id));
+        }
+    }
+    Ok(())
+}
+
+// Before v3 every active file owned its chunks under its own id. Register
+// them as blobs and drop the chunks of files whose content is already stored.
+fn index_blobs(state: &mut State) {
+    let mut active: Vec<_> = state.files.values().filter(|m| m.is_active).collect();
+    active.sort_by_key(|m| m.uploaded_at);
+
+    for metadata in active {
+        let Some(file_hash) = metadata.file_hash.clone() else { continue };
+        match state.blobs.get_mut(&file_hash) {
+            Some(blob) => {
+                blob.ref_count += 1;
+                chunk_store::remove_all(&metadata.id);
+            }
+            None => {
+                state.blobs.insert(file_hash, StoredBlob {
+                    storage_key: metadata.id.clone(),
+                    size: metadata.size,
+                    ref_count: 1,
+                });
+            }
+        }
+    }
+}
+
+mod v1 {
+    use crate::{chunk_store, Config, FileChunk, FileMetadata, Role, Session, User};
+    use candid::Principal;
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Deserialize)]
+    pub(super) struct UploadSession {
+        owner: Principal,
+        filename: String,
+        chunk_count: u32,
+        total_size: u64,
+        received_bytes: u64,
+        chunks: BTreeMap<u32, Vec<u8>>,
+        last_activity: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct State {
+        files: HashMap<String, FileMetadata>,
+        chunks: HashMap<String, Vec<FileChunk>>,
+        uploads: HashMap<String, UploadSession>,
+        users: HashMap<Principal, User>,
+        roles: HashMap<Principal, Vec<Role>>,
+        sessions: HashMap<String, Session>,
+        config: Config,
+        id_counter: u64,
+    }
+
+    impl State {
+        // Moves every heap chunk into the chunk store.
+        pub(super) fn migrate(self) -> crate::State {
+            let mut files = self.files;
+            for (file_id, chunks) in self.chunks {
+                let mut chunk_sizes = Vec::with_capacity(chunks.len());
+                for chunk in chunks {
+                    chunk_sizes.push(chunk.data.len() as u64);
+                    chunk_store::put(&file_id, chunk.index, chunk.data);
+                }
+                if let Some(metadata) = files.get_mut(&file_id) {
+                    metadata.chunk_sizes = chunk_sizes;
+                }
+            }
+
+            let uploads = self
+                .uploads
+                .into_iter()
+                .map(|(upload_id, upload)| {
+                    let mut chunks = BTreeMap::new();
+                    for (index, data) in upload.chunks {
+                        chunks.insert(index, data.len() as u64);
+                        chunk_store::put(&upload_id, index, data);
+                    }
+                    let session = crate::UploadSession {
+                        owner: upload.owner,
+                        filename: upload.filename,
+                        chunk_count: upload.chunk_count,
+                        total_size: upload.total_size,
+                        received_bytes: upload.received_bytes,
+                        chunks,
+                        last_activity: upload.last_activity,
+                        content_type: None,
+                        target: None,
+                        target_revision: 0,
+                        acl: None,
+                    };
+                    (upload_id, session)
+                })
+                .collect();
+
+            // Everything v1 had no notion of starts out empty.
+            crate::State {
+                files,
+                uploads,
+                users: self.users.into_iter().collect(),
+                legacy_roles: self.roles,
+                sessions: self.sessions,
+                config: self.config,
+                id_counter: self.id_counter,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileEncoding, FileMetadata, Role, Session};
+    use candid::Principal;
+    use serde::Serialize;
+    use serde_cbor::Value;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    // Users carried a single, never updated `role` before v8.
+    #[derive(Serialize)]
+    struct OldUser {
+        id: String,
+        username: String,
+        role: Role,
+        email: Option<String>,
+        is_active: bool,
+    }
+
+    impl OldUser {
+        fn new(principal: Principal, username: &str, role: Role) -> Self {
+            OldUser {
+                id: principal.to_text(),
+                username: username.to_string(),
+                role,
+                email: None,
+                is_active: true,
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct OldConfig {
+        max_file_size_bytes: u64,
+        uploads_enabled: bool,
+        cdn_domain: Option<String>,
+        last_updated_nanos: u64,
+    }
+
+    #[derive(Serialize)]
+    struct V1Chunk {
+        file_id: String,
+        index: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct V1Upload {
+        owner: Principal,
+        filename: String,
+        chunk_count: u32,
+        total_size: u64,
+        received_bytes: u64,
+        chunks: BTreeMap<u32, Vec<u8>>,
+        last_activity: u64,
+    }
+
+    #[derive(Serialize)]
+    struct V1State {
+        files: HashMap<String, FileMetadata>,
+        chunks: HashMap<String, Vec<V1Chunk>>,
+        uploads: HashMap<String, V1Upload>,
+        users: HashMap<Principal, OldUser>,
+        roles: HashMap<Principal, Vec<Role>>,
+        sessions: HashMap<String, Session>,
+        config: OldConfig,
+        id_counter: u64,
+    }
+
+    // A file as v1 stored it: no path, revisions or chunk sizes yet.
+    fn v1_file(id: &str, filename: &str, owner: Principal, uploaded_at: u64, content: &[u8]) -> FileMetadata {
+        let mut metadata = FileMetadata::for_tests(id, "", owner, uploaded_at);
+        metadata.filename = filename.to_string();
+        metadata.size = content.len() as u64;
+        metadata.chunk_count = 1;
+        metadata.file_hash = Some(crate::hash_data(content));
+        metadata
+    }
+
+    fn session(user_id: Principal, roles: Vec<Role>) -> Session {
+        Session { user_id, expires_at: u64::MAX, roles }
+    }
+
+    #[test]
+    fn migrates_a_v1_snapshot() {
+        let (publisher, viewer) = (principal(1), principal(2));
+        let files = [
+            v1_file("f1", "a.txt", publisher, 1, b"hello"),
+            v1_file("f2", "b.txt", publisher, 2, b"hello"),
+            v1_file("f3", "a.txt", publisher, 3, b"world"),
+        ];
+        let snapshot = V1State {
+            chunks: files.iter()
+                .map(|metadata| {
+                    let data = if metadata.id == "f3" { b"world" } else { b"hello" };
+                    (metadata.id.clone(), vec![V1Chunk { file_id: metadata.id.clone(), index: 0, data: data.to_vec() }])
+                })
+                .collect(),
+            files: files.into_iter().map(|metadata| (metadata.id.clone(), metadata)).collect(),
+            uploads: HashMap::from([("u1".to_string(), V1Upload {
+                owner: publisher,
+                filename: "c.txt".to_string(),
+                chunk_count: 2,
+                total_size: 6,
+                received_bytes: 3,
+                chunks: BTreeMap::from([(0, b"abc".to_vec())]),
+                last_activity: 4,
+            })]),
+            users: HashMap::from([(publisher, OldUser::new(publisher, "pub", Role::Viewer))]),
+            roles: HashMap::from([
+                (publisher, vec![Role::Admin, Role::Publisher]),
+                (viewer, vec![Role::Viewer, Role::Viewer]),
+            ]),
+            sessions: HashMap::from([("s1".to_string(), session(publisher, vec![Role::Admin]))]),
+            config: OldConfig {
+                max_file_size_bytes: 1024,
+                uploads_enabled: true,
+                cdn_domain: None,
+                last_updated_nanos: 0,
+            },
+            id_counter: 7,
+        };
+
+        let state = decode(1, serde_cbor::to_vec(&snapshot).unwrap().as_slice()).unwrap();
+
+        assert_eq!(state.users[&publisher].username, "pub");
+        assert_eq!(state.users[&publisher].roles, vec![Role::Admin, Role::Publisher]);
+        assert_eq!(state.users[&viewer].username, viewer.to_text());
+        assert_eq!(state.users[&viewer].roles, vec![Role::Viewer]);
+        assert!(state.legacy_roles.is_empty());
+
+        // Identical content is stored once, under the oldest file.
+        let hello = &state.blobs[&crate::hash_data(b"hello")];
+        assert_eq!((hello.storage_key.as_str(), hello.ref_count), ("f1", 2));
+        assert_eq!(state.blobs[&crate::hash_data(b"world")].ref_count, 1);
+        assert_eq!(chunk_store::get("f1", 0).as_deref(), Some(&b"hello"[..]));
+        assert_eq!(chunk_store::get("f2", 0), None);
+        assert_eq!(state.files["f2"].chunk_sizes, vec![5]);
+
+        // The newest file keeps a contested path.
+        assert_eq!(state.paths.get("/a.txt").map(String::as_str), Some("f3"));
+        assert_eq!(state.paths.get("/b.txt").map(String::as_str), Some("f2"));
+        assert_eq!(state.files["f1"].path, "/f1/a.txt");
+        assert_eq!(state.files["f1"].current_revision, 1);
+        assert_eq!(state.index.owned_by(&publisher).count(), 3);
+
+        assert_eq!(state.uploads["u1"].chunks, BTreeMap::from([(0, 3)]));
+        assert_eq!(chunk_store::get("u1", 0).as_deref(), Some(&b"abc"[..]));
+        assert!(state.user_sessions[&publisher].contains("s1"));
+        assert_eq!(state.config.max_file_size_bytes, 1024);
+        assert_eq!(state.id_counter, 7);
+    }
+
+    #[test]
+    fn migrates_a_v7_snapshot() {
+        let (admin, publisher) = (principal(1), principal(2));
+        let mut state = State::default();
+        let mut metadata = FileMetadata::for_tests("f1", "/site/app.js", publisher, 1);
+        metadata.file_hash = Some("hash-1".to_string());
+        state.index.insert(&metadata);
+        state.paths.insert(metadata.path.clone(), metadata.id.clone());
+        state.files.insert(metadata.id.clone(), metadata);
+        state.blobs.insert("hash-1".to_string(), StoredBlob {
+            storage_key: "f1".to_string(),
+            size: 0,
+            ref_count: 1,
+        });
+        for (session_id, user_id, roles) in [("s-admin", admin, vec![Role::Admin]), ("s-pub", publisher, vec![Role::Publisher])] {
+            state.sessions.insert(session_id.to_string(), session(user_id, roles));
+            state.user_sessions.entry(user_id).or_default().insert(session_id.to_string());
+        }
+
+        // v7 kept the roles apart; the admin set up by `init` had no user record.
+        let mut snapshot = serde_cbor::value::to_value(&state).unwrap();
+        let Value::Map(fields) = &mut snapshot else { panic!("state is not encoded as a map") };
+        let users = HashMap::from([(publisher, OldUser::new(publisher, "pub", Role::Viewer))]);
+        let roles = HashMap::from([(admin, vec![Role::Admin]), (publisher, vec![Role::Publisher, Role::Viewer])]);
+        fields.insert(Value::Text("users".to_string()), serde_cbor::value::to_value(users).unwrap());
+        fields.insert(Value::Text("roles".to_string()), serde_cbor::value::to_value(roles).unwrap());
+
+        let state = decode(7, serde_cbor::to_vec(&snapshot).unwrap().as_slice()).unwrap();
+
+        assert_eq!(state.users[&admin].roles, vec![Role::Admin]);
+        assert_eq!(state.users[&admin].username, admin.to_text());
+        assert!(state.users[&admin].is_active);
+        assert_eq!(state.users[&publisher].username, "pub");
+        assert_eq!(state.users[&publisher].roles, vec![Role::Publisher, Role::Viewer]);
+        assert!(state.user_sessions[&admin].contains("s-admin"));
+        assert!(state.user_sessions[&publisher].contains("s-pub"));
+        assert_eq!(state.blobs["hash-1"].ref_count, 1);
+        assert_eq!(state.paths.get("/site/app.js").map(String::as_str), Some("f1"));
+        assert_eq!(state.index.owned_by(&publisher).count(), 1);
+    }
+
+    #[test]
+    fn drops_brotli_encodings_and_their_blobs() {
+        let mut state = State::default();
+        let mut metadata = FileMetadata::for_tests("f1", "/app.js", principal(1), 1);
+        let brotli = FileEncoding {
+            size: 3,
+            chunk_count: 1,
+            file_hash: "hash-br".to_string(),
+            chunk_sizes: vec![3],
+        };
+        metadata.encodings.insert(encoding::BROTLI.to_string(), brotli.clone());
+        metadata.revisions = vec![FileRevision {
+            revision: 1,
+            size: 0,
+            chunk_count: 0,
+            chunk_sizes: Vec::new(),
+            file_hash: String::new(),
+            mime_type: metadata.mime_type.clone(),
+            encodings: BTreeMap::from([(encoding::BROTLI.to_string(), brotli)]),
+            uploaded_by: principal(1),
+            uploaded_at: 1,
+        }];
+        state.files.insert(metadata.id.clone(), metadata);
+        state.blobs.insert("hash-br".to_string(), StoredBlob {
+            storage_key: "blob-br".to_string(),
+            size: 3,
+            ref_count: 1,
+        });
+        chunk_store::put("blob-br", 0, b"brr".to_vec());
+
+        let snapshot = serde_cbor::to_vec(&state).unwrap();
+        let state = decode(8, snapshot.as_slice()).unwrap();
+
+        assert!(state.files["f1"].encodings.is_empty());
+        assert!(state.files["f1"].revisions[0].encodings.is_empty());
+        assert!(!state.blobs.contains_key("hash-br"));
+        assert!(chunk_store::get("blob-br", 0).is_none());
+    }
+
+    #[test]
+    fn refuses_a_session_of_an_unknown_user() {
+        let mut state = State::default();
+        state.sessions.insert("s1".to_string(), session(principal(1), vec![Role::Viewer]));
+        state.user_sessions.entry(principal(1)).or_default().insert("s1".to_string());
+
+        let snapshot = serde_cbor::to_vec(&state).unwrap();
+        assert!(decode(STATE_VERSION, snapshot.as_slice()).is_err());
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let mut state = State::default();
+        state.seed_admins(vec![principal(1)]);
+        state.id_counter = 42;
+        save_state(&state).unwrap();
+
+        let loaded = load_state().unwrap().unwrap();
+        assert_eq!(loaded.users[&principal(1)].roles, vec![Role::Admin]);
+        assert_eq!(loaded.id_counter, 42);
+    }
+}
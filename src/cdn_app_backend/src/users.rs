@@ -0,0 +1,36 @@
+// User profiles. A deactivated user loses their sessions and is refused by
+// every endpoint that checks the caller; without a session they can read
+// public files like anyone else, and nothing more.
+use crate::User;
+use candid::{CandidType, Deserialize};
+
+const MAX_USERNAME_LEN: usize = 64;
+const MAX_EMAIL_LEN: usize = 254;
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct UserPage {
+    pub users: Vec<User>,
+    pub next_cursor: Option<String>,
+}
+
+pub(crate) fn validate_username(username: &str) -> Result<(), String> {
+    let len = username.chars().count();
+    if len == 0 || len > MAX_USERNAME_LEN {
+        return Err(format!("Username must be between 1 and {} characters", MAX_USERNAME_LEN));
+    }
+    if username.chars().any(|c| c.is_control()) {
+        return Err("Username contains control characters".to_string());
+    }
+    Ok(())
+}
+
+// Only catches obvious mistakes; the address is never used to send mail.
+pub(crate) fn validate_email(email: &str) -> Result<(), String> {
+    let valid = email.len() <= MAX_EMAIL_LEN
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && email.split_once('@').is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+    if !valid {
+        return Err(format!("Invalid email address: {}", email));
+    }
+    Ok(())
+}
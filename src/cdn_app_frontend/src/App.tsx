@@ -1,5 +1,5 @@
 import React, { useEffect, useState } from 'react'
-import { listFiles, uploadFile, getFile, deleteFile, whoami, login, register, logout, grantRole, revokeRole, listAllUserRoles, getConfig, updateConfig, resetConfig, wipeAll } from './api/actor'
+import { errorMessage, listFiles, uploadFile, getFile, deleteFile, whoami, login, register, logout, grantRole, revokeRole, listAllUserRoles, getConfig, updateConfig, resetConfig, wipeAll } from './api/actor'
 
 function bytesToBlobUrl(arr: number[], filename: string){
   const u8 = new Uint8Array(arr)
@@ -13,6 +13,7 @@ export default function App(){
   const [error, setError] = useState<string | null>(null)
   const [toast, setToast] = useState<string | null>(null)
   const [who, setWho] = useState<string>('')
+  const [sessionId, setSessionId] = useState<string | null>(null)
   const [rolesMap, setRolesMap] = useState<any>(null)
   const [config, setConfig] = useState<any>(null)
   const [page, setPage] = useState(0)
@@ -23,8 +24,8 @@ export default function App(){
     try{
       setLoading(true)
       const r = await listFiles()
-      if(r.ok) setFiles(r.ok)
-      else setError(r.err)
+      if('Ok' in r) setFiles(r.Ok)
+      else setError(errorMessage(r.Err))
     }catch(e:any){ setError(String(e)) }
     setLoading(false)
   }
@@ -37,10 +38,10 @@ export default function App(){
     try{
       setLoading(true)
       const buf = new Uint8Array(await file.arrayBuffer())
-      const res = await uploadFile(file.name, Array.from(buf))
+      const res = await uploadFile(file.name, Array.from(buf), file.type || undefined)
       setLoading(false)
-      if(res.ok){ setToast('Uploaded'); refresh() }
-      else setError(res.err)
+      if('Ok' in res){ setToast('Uploaded'); refresh() }
+      else setError(errorMessage(res.Err))
     }catch(e:any){ setLoading(false); setError(String(e)) }
   }
 
@@ -70,9 +71,9 @@ export default function App(){
                 <div className="controls">
                   <button onClick={async ()=>{
                     const g = await getFile(f.id)
-                    if(g.ok){ const url = bytesToBlobUrl(g.ok.content, f.filename); const a = document.createElement('a'); a.href = url; a.download = f.filename; document.body.appendChild(a); a.click(); a.remove(); URL.revokeObjectURL(url); setToast('Downloaded') } else setError(g.err)
+                    if('Ok' in g){ const url = bytesToBlobUrl(g.Ok.content, f.filename); const a = document.createElement('a'); a.href = url; a.download = f.filename; document.body.appendChild(a); a.click(); a.remove(); URL.revokeObjectURL(url); setToast('Downloaded') } else setError(errorMessage(g.Err))
                   }}>Download</button>
-                  <button className="secondary" onClick={async ()=>{ if(!confirm('Delete?')) return; const d = await deleteFile(f.id); if(d.ok){ setToast('Deleted'); refresh() } else setError(d.err) }}>Delete</button>
+                  <button className="secondary" onClick={async ()=>{ if(!confirm('Delete?')) return; const d = await deleteFile(f.id); if('Ok' in d){ setToast('Deleted'); refresh() } else setError(errorMessage(d.Err)) }}>Delete</button>
                 </div>
               </div>
             ))}
@@ -96,9 +97,9 @@ export default function App(){
 
             <div>
               <input id="authName" placeholder="username" />
-              <button onClick={async ()=>{ const n = (document.getElementById('authName') as HTMLInputElement).value || 'guest'; const r = await login(n); setToast(JSON.stringify(r)) }}>Login</button>
+              <button onClick={async ()=>{ const r = await login(); if('Ok' in r){ setSessionId(r.Ok[0]); setToast('Logged in') } else setError(errorMessage(r.Err)) }}>Login</button>
               <button onClick={async ()=>{ const n = (document.getElementById('authName') as HTMLInputElement).value || 'guest'; const r = await register(n); setToast(JSON.stringify(r)) }}>Register</button>
-              <button onClick={async ()=>{ const r = await logout(); setToast('logout') }}>Logout</button>
+              <button onClick={async ()=>{ if(sessionId) await logout(sessionId); setSessionId(null); setToast('logout') }}>Logout</button>
             </div>
 
             <div>
@@ -6,18 +6,98 @@ const agent = new HttpAgent({ host: 'http://127.0.0.1:4943' })
 agent.fetchRootKey().catch(()=>{})
 const actor = Actor.createActor(idlFactory, { agent, canisterId: CANISTER_ID })
 
+// Every method that can fail answers { Ok: value } or { Err: DomainError },
+// where the error is a single-key variant such as { LimitExceeded: '...' }.
+// Branch on the kind; the message is for people.
+export type DomainErrorKind =
+  'InvalidInput' | 'InvalidRole' | 'InvalidState' | 'Forbidden' | 'Unauthorized' | 'NotFound' | 'InvalidData' |
+  'DuplicateEntry' | 'DataCorruption' | 'ServiceUnavailable' | 'ConfigError' | 'LimitExceeded' | 'Other'
+export type DomainError = { [K in DomainErrorKind]: { [P in K]: string } }[DomainErrorKind]
+export type Result<T> = { Ok: T } | { Err: DomainError }
+export const errorKind = (err: DomainError): DomainErrorKind => Object.keys(err)[0] as DomainErrorKind
+export const errorMessage = (err: DomainError): string => {
+  const kind = errorKind(err)
+  return `${kind}: ${(err as Record<string, string>)[kind]}`
+}
+
 export const listFiles = () => actor.list_files()
-export const uploadFile = (name: string, bytes: number[]) => actor.upload_file(name, bytes)
-export const getFile = (id: string) => actor.get_file(id)
+export type SortBy = 'UploadedAt' | 'Size' | 'Filename'
+export type FileFilter = { uploader?: string, mimeType?: string, namePrefix?: string, fileHash?: string, uploadedAfter?: bigint, uploadedBefore?: bigint }
+const opt = <T,>(value: T | undefined): [] | [T] => value === undefined ? [] : [value]
+export const listFilesPaged = (opts: { cursor?: string, limit?: number, sortBy?: SortBy, descending?: boolean, filter?: FileFilter } = {}) => actor.list_files_paged(
+  opt(opts.cursor),
+  opt(opts.limit),
+  opts.sortBy ? [{ [opts.sortBy]: null }] : [],
+  opts.descending === undefined ? [] : [opts.descending ? { Desc: null } : { Asc: null }],
+  opts.filter ? [{
+    uploader: opt(opts.filter.uploader),
+    mime_type: opt(opts.filter.mimeType),
+    name_prefix: opt(opts.filter.namePrefix),
+    file_hash: opt(opts.filter.fileHash),
+    uploaded_after: opt(opts.filter.uploadedAfter),
+    uploaded_before: opt(opts.filter.uploadedBefore),
+  }] : [],
+)
+export type Grantee = { Principal: any } | { Role: any }
+export type FileAcl = { read: Grantee[], write: Grantee[], delete: Grantee[] }
+export const uploadFile = (name: string, bytes: number[], contentType?: string, acl?: FileAcl) => actor.upload_file(name, bytes, contentType ? [contentType] : [], acl ? [acl] : [])
+export const beginUpload = (name: string, chunkCount: number, totalSize: number, contentType?: string, acl?: FileAcl) => actor.begin_upload(name, chunkCount, BigInt(totalSize), contentType ? [contentType] : [], acl ? [acl] : [])
+export const putChunk = (uploadId: string, index: number, bytes: number[]) => actor.put_chunk(uploadId, index, bytes)
+export const commitUpload = (uploadId: string) => actor.commit_upload(uploadId)
+export const uploadEncoding = (id: string, encoding: string, bytes: number[]) => actor.upload_encoding(id, encoding, bytes)
+export const abortUpload = (uploadId: string) => actor.abort_upload(uploadId)
+export const getFile = (id: string, revision?: number, token?: string) => actor.get_file(id, revision === undefined ? [] : [revision], token ? [token] : [])
+export const getFileSigned = (id: string, token: string, revision?: number) => actor.get_file_signed(id, token, revision === undefined ? [] : [revision])
+export const listRevisions = (path: string) => actor.list_revisions(path)
+export const rollbackFile = (path: string, revision: number) => actor.rollback_file(path, revision)
+export const getFileInfo = (id: string) => actor.get_file_info(id)
+export const getFileChunk = (id: string, index: number) => actor.get_file_chunk(id, index)
+export const getFileRange = (id: string, offset: number, length: number) => actor.get_file_range(id, BigInt(offset), BigInt(length))
 export const deleteFile = (id: string) => actor.delete_file(id)
+export const listDirectory = (prefix: string, delimiter = '/') => actor.list_directory(prefix, delimiter)
+export const moveFile = (path: string, newPath: string) => actor.move_file(path, newPath)
+export const renameFile = (path: string, newName: string) => actor.rename_file(path, newName)
+export const getFileAcl = (id: string) => actor.get_file_acl(id)
+export const setFileAcl = (id: string, acl: FileAcl) => actor.set_file_acl(id, acl)
+export type Visibility = 'Public' | 'Private'
+export const setFileVisibility = (id: string, visibility?: Visibility) => actor.set_file_visibility(id, visibility ? [{ [visibility]: null }] : [])
+export const setDirectoryVisibility = (path: string, visibility?: Visibility) => actor.set_directory_visibility(path, visibility ? [{ [visibility]: null }] : [])
+export const listDirectoryVisibility = () => actor.list_directory_visibility()
+export const createSignedUrl = (id: string, ttlSecs: number, maxUses?: number) => actor.create_signed_url(id, BigInt(ttlSecs), maxUses === undefined ? [] : [maxUses])
+export const listSignedUrls = (id: string) => actor.list_signed_urls(id)
+export const revokeSignedUrl = (linkId: string) => actor.revoke_signed_url(linkId)
 export const whoami = () => actor.whoami()
-export const login = (name: string) => actor.login(name)
-export const register = (name: string) => actor.register(name)
-export const logout = () => actor.logout()
+// Sessions are bound to the calling principal; the id is only needed to end one.
+export const login = (scope?: any[]) => actor.login(opt(scope))
+export const register = (name: string, email?: string) => actor.register(name, opt(email))
+export const logout = (sessionId: string) => actor.logout(sessionId)
+export const verifySession = (sessionId: string) => actor.verify_session(sessionId)
+export const getMyProfile = () => actor.get_my_profile()
+// Leave a field undefined to keep it; pass null as the email to clear it.
+export const updateProfile = (username?: string, email?: string | null) => actor.update_profile(opt(username), email === undefined ? [] : [email === null ? [] : [email]])
+export const listUsers = (cursor?: string, limit?: number) => actor.list_users(opt(cursor), opt(limit))
+export const deactivateUser = (principal: string) => actor.deactivate_user(principal)
+export const reactivateUser = (principal: string) => actor.reactivate_user(principal)
 export const grantRole = (user: string, role: any) => actor.grant_role(user, role)
 export const revokeRole = (user: string, role: any) => actor.revoke_role(user, role)
+export const proposeAdminTransfer = (to: string) => actor.propose_admin_transfer(to)
+export const acceptAdminTransfer = () => actor.accept_admin_transfer()
+export const cancelAdminTransfer = (to: string) => actor.cancel_admin_transfer(to)
 export const listAllUserRoles = () => actor.list_all_user_roles()
 export const getConfig = () => actor.get_config()
-export const updateConfig = (max?: number|null, uploads?: boolean|null, domain?: string|null|undefined) => actor.update_config(max ?? null, uploads ?? null, domain === undefined ? undefined : (domain === null ? null : domain))
+// Leave a setting undefined (or null) to keep it; pass null as the domain to clear it.
+export const updateConfig = (max?: number|null, uploads?: boolean|null, domain?: string|null, autoCompress?: boolean|null) => actor.update_config(
+  opt(max == null ? undefined : BigInt(max)),
+  opt(uploads ?? undefined),
+  domain === undefined ? [] : [domain === null ? [] : [domain]],
+  opt(autoCompress ?? undefined),
+)
 export const resetConfig = () => actor.reset_config()
+export type RateLimit = { capacity: number, refill_per_minute: number }
+export const setRateLimit = (method: string, limit?: RateLimit) => actor.set_rate_limit(method, limit ? [limit] : [])
 export const wipeAll = () => actor.wipe_all()
+export type Quota = { max_bytes: [] | [bigint], max_files: [] | [bigint] }
+export const getUsage = (principal: string) => actor.get_usage(principal)
+export const setUserQuota = (principal: string, quota?: Quota) => actor.set_user_quota(principal, quota ? [quota] : [])
+export const setRoleQuota = (role: any, quota: Quota) => actor.set_role_quota(role, quota)
+export const getMaintenanceStatus = () => actor.get_maintenance_status()